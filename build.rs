@@ -0,0 +1,11 @@
+use std::{env, path::PathBuf};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Use a vendored protoc so that building doesn't depend on whatever (if anything) is installed on the machine.
+    env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("admin_descriptor.bin"))
+        .compile_protos(&["proto/admin.proto"], &["proto"])?;
+    Ok(())
+}
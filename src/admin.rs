@@ -0,0 +1,411 @@
+//! A gRPC service for operating the server by hand: trigger, inspect or abort a drain, force a shutdown, start an
+//! upgrade, and pin the health status of individual services. See `proto/admin.proto`.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tonic::{Request, Response, Status};
+
+use crate::{
+    lifecycle::{Phase, ShutdownReason},
+    ServerHandle,
+};
+
+pub mod pb {
+    tonic::include_proto!("tonic_shutdown.admin.v1");
+
+    /// The encoded `FileDescriptorSet` for the admin service, for registering with `tonic_reflection`.
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("admin_descriptor");
+}
+
+use pb::{
+    admin_server::{Admin, AdminServer},
+    drain_status, AbortDrainRequest, ClearServingStatusRequest, DrainRequest, DrainStatus,
+    ForceShutdownRequest, GetDrainStatusRequest, GetServingStatusesRequest, ServingStatus,
+    ServingStatuses, SetServingStatusRequest, UpgradeRequest, UpgradeResponse,
+};
+
+/// Lets operators trigger, inspect and cancel a drain over gRPC. See `proto/admin.proto`.
+pub struct AdminService {
+    handle: ServerHandle,
+}
+
+impl AdminService {
+    pub fn new(handle: ServerHandle) -> AdminServer<Self> {
+        AdminServer::new(Self { handle })
+    }
+
+    fn status(&self) -> DrainStatus {
+        let phase = self.handle.state();
+        let now = Instant::now();
+        let remaining_ms = |until: Instant| until.saturating_duration_since(now).as_millis() as u64;
+        let registry = self.handle.registry();
+        DrainStatus {
+            phase: drain_status::Phase::from(&phase) as i32,
+            reason: phase.reason().map(ToString::to_string).unwrap_or_default(),
+            lame_duck_remaining_ms: match phase {
+                Phase::LameDuck { until, .. } => Some(remaining_ms(until)),
+                _ => None,
+            },
+            grace_remaining_ms: phase.deadline().map(remaining_ms),
+            open_connections: registry.connections().len() as u64,
+            streams: registry
+                .streams()
+                .into_iter()
+                .map(|stream| pb::Stream {
+                    kind: stream.kind.to_string(),
+                    peer: stream.peer.map(|peer| peer.to_string()).unwrap_or_default(),
+                    open_ms: stream.started_at.elapsed().as_millis() as u64,
+                    method: stream.method,
+                })
+                .collect(),
+        }
+    }
+
+    async fn serving_statuses(&self) -> Result<ServingStatuses, Status> {
+        let services = self
+            .handle
+            .health()
+            .ok_or_else(no_health_reporter)?
+            .statuses()
+            .await
+            .into_iter()
+            .map(|(service, status, pinned)| pb::ServiceStatus {
+                service,
+                status: ServingStatus::from(status) as i32,
+                pinned,
+            })
+            .collect();
+        Ok(ServingStatuses { services })
+    }
+}
+
+impl From<&Phase> for drain_status::Phase {
+    fn from(phase: &Phase) -> Self {
+        match phase {
+            Phase::Starting => drain_status::Phase::Starting,
+            Phase::Serving => drain_status::Phase::Serving,
+            Phase::LameDuck { .. } => drain_status::Phase::LameDuck,
+            Phase::Draining { .. } => drain_status::Phase::Draining,
+            Phase::ForceClosing { .. } => drain_status::Phase::ForceClosing,
+            Phase::Stopped { .. } => drain_status::Phase::Stopped,
+        }
+    }
+}
+
+impl From<tonic_health::ServingStatus> for ServingStatus {
+    fn from(status: tonic_health::ServingStatus) -> Self {
+        match status {
+            tonic_health::ServingStatus::Unknown => ServingStatus::Unspecified,
+            tonic_health::ServingStatus::Serving => ServingStatus::Serving,
+            tonic_health::ServingStatus::NotServing => ServingStatus::NotServing,
+        }
+    }
+}
+
+fn no_health_reporter() -> Status {
+    Status::failed_precondition("the server has no health reporter")
+}
+
+fn admin_reason(reason: String) -> ShutdownReason {
+    ShutdownReason::Admin(if reason.is_empty() {
+        "no reason given".to_owned()
+    } else {
+        reason
+    })
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    async fn drain(&self, request: Request<DrainRequest>) -> Result<Response<DrainStatus>, Status> {
+        let DrainRequest {
+            reason,
+            grace_period_ms,
+            lame_duck_ms,
+        } = request.into_inner();
+        let lame_duck = lame_duck_ms.map_or(self.handle.lame_duck(), Duration::from_millis);
+        let grace_period = grace_period_ms
+            .map(Duration::from_millis)
+            .or(self.handle.grace_period());
+        if let Some(grace_period) = grace_period {
+            // Same rule as `Config::validate`: the grace period has to cover the lame-duck period.
+            if grace_period < lame_duck {
+                return Err(Status::invalid_argument(format!(
+                    "grace period ({}ms) is shorter than the lame-duck period ({}ms), so streams would be cut off \
+                     before the drain even starts",
+                    grace_period.as_millis(),
+                    lame_duck.as_millis()
+                )));
+            }
+        }
+        let started =
+            self.handle
+                .lifecycle()
+                .begin_shutdown(admin_reason(reason), lame_duck, grace_period);
+        if !started {
+            return Err(Status::failed_precondition(format!(
+                "already {}",
+                self.handle.state()
+            )));
+        }
+        Ok(Response::new(self.status()))
+    }
+
+    async fn get_drain_status(
+        &self,
+        _request: Request<GetDrainStatusRequest>,
+    ) -> Result<Response<DrainStatus>, Status> {
+        Ok(Response::new(self.status()))
+    }
+
+    async fn force_shutdown(
+        &self,
+        request: Request<ForceShutdownRequest>,
+    ) -> Result<Response<DrainStatus>, Status> {
+        let lifecycle = self.handle.lifecycle();
+        lifecycle.begin_shutdown(
+            admin_reason(request.into_inner().reason),
+            Duration::ZERO,
+            Some(Duration::ZERO),
+        );
+        if !lifecycle.force_close() && !self.handle.state().is_force_closing() {
+            return Err(Status::failed_precondition(format!(
+                "already {}",
+                self.handle.state()
+            )));
+        }
+        Ok(Response::new(self.status()))
+    }
+
+    async fn abort_drain(
+        &self,
+        _request: Request<AbortDrainRequest>,
+    ) -> Result<Response<DrainStatus>, Status> {
+        if !self.handle.lifecycle().abort_shutdown() {
+            if let Phase::LameDuck {
+                abortable: false, ..
+            } = self.handle.state()
+            {
+                return Err(Status::failed_precondition(
+                    "cannot abort a drain that began before the server was serving",
+                ));
+            }
+            return Err(Status::failed_precondition(format!(
+                "can only abort a drain during the lame-duck period, but the server is {}",
+                self.handle.state()
+            )));
+        }
+        Ok(Response::new(self.status()))
+    }
+
+    async fn upgrade(
+        &self,
+        _request: Request<UpgradeRequest>,
+    ) -> Result<Response<UpgradeResponse>, Status> {
+        match self.handle.upgrade().await {
+            Ok(pid) => Ok(Response::new(UpgradeResponse { pid })),
+            Err(err) => Err(Status::failed_precondition(format!("{err:#}"))),
+        }
+    }
+
+    async fn get_serving_statuses(
+        &self,
+        _request: Request<GetServingStatusesRequest>,
+    ) -> Result<Response<ServingStatuses>, Status> {
+        Ok(Response::new(self.serving_statuses().await?))
+    }
+
+    async fn set_serving_status(
+        &self,
+        request: Request<SetServingStatusRequest>,
+    ) -> Result<Response<ServingStatuses>, Status> {
+        let SetServingStatusRequest { service, status } = request.into_inner();
+        let status = match ServingStatus::try_from(status) {
+            Ok(ServingStatus::Serving) => tonic_health::ServingStatus::Serving,
+            Ok(ServingStatus::NotServing) => tonic_health::ServingStatus::NotServing,
+            _ => {
+                return Err(Status::invalid_argument(
+                    "status must be SERVING_STATUS_SERVING or SERVING_STATUS_NOT_SERVING",
+                ))
+            }
+        };
+        self.handle
+            .health()
+            .ok_or_else(no_health_reporter)?
+            .set_override(&service, status)
+            .await
+            .map_err(|err| Status::not_found(err.to_string()))?;
+        Ok(Response::new(self.serving_statuses().await?))
+    }
+
+    async fn clear_serving_status(
+        &self,
+        request: Request<ClearServingStatusRequest>,
+    ) -> Result<Response<ServingStatuses>, Status> {
+        self.handle
+            .health()
+            .ok_or_else(no_health_reporter)?
+            .clear_override(&request.into_inner().service)
+            .await
+            .map_err(|err| Status::not_found(err.to_string()))?;
+        Ok(Response::new(self.serving_statuses().await?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use pb::admin_client::AdminClient;
+    use tonic::Code;
+
+    use super::*;
+    use crate::{health, listener::ListenAddr, GracefulServer};
+
+    type Client = AdminClient<AdminServer<AdminService>>;
+
+    async fn serve(server: GracefulServer) -> (ServerHandle, Client) {
+        let address = ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 0)));
+        let handle = server.serve(&[address]).await.unwrap();
+        let client = AdminClient::new(AdminService::new(handle.clone()));
+        (handle, client)
+    }
+
+    fn drain(lame_duck_ms: u64, grace_period_ms: Option<u64>) -> DrainRequest {
+        DrainRequest {
+            reason: "test".to_owned(),
+            grace_period_ms,
+            lame_duck_ms: Some(lame_duck_ms),
+        }
+    }
+
+    #[tokio::test]
+    async fn drain_needs_a_grace_period_that_covers_the_lame_duck() {
+        let (handle, mut client) = serve(GracefulServer::builder()).await;
+        let err = client.drain(drain(1000, Some(100))).await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument, "{err}");
+        // Nor does it fall back on the configured grace period when that is too short.
+        let (_, mut short) =
+            serve(GracefulServer::builder().grace_period(Some(Duration::from_millis(100)))).await;
+        let err = short.drain(drain(1000, None)).await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument, "{err}");
+        assert_eq!(handle.state(), Phase::Serving);
+    }
+
+    #[tokio::test]
+    async fn drain_then_abort() {
+        let (handle, mut client) = serve(GracefulServer::builder()).await;
+        let status = client
+            .drain(drain(60_000, None))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(status.phase(), drain_status::Phase::LameDuck);
+        assert_eq!(status.reason, "admin request: test");
+        assert!(status.lame_duck_remaining_ms.unwrap() > 0);
+        assert_eq!(status.grace_remaining_ms, None);
+
+        let err = client.drain(drain(0, None)).await.unwrap_err();
+        assert_eq!(err.code(), Code::FailedPrecondition, "{err}");
+
+        let status = client
+            .abort_drain(AbortDrainRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(status.phase(), drain_status::Phase::Serving);
+        assert_eq!(handle.state(), Phase::Serving);
+        let err = client.abort_drain(AbortDrainRequest {}).await.unwrap_err();
+        assert_eq!(err.code(), Code::FailedPrecondition, "{err}");
+    }
+
+    #[tokio::test]
+    async fn force_shutdown_skips_the_grace_period() {
+        let (handle, mut client) = serve(GracefulServer::builder()).await;
+        client.drain(drain(60_000, None)).await.unwrap();
+        let status = client
+            .force_shutdown(ForceShutdownRequest {
+                reason: "stuck".to_owned(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        // The drain that was already under way keeps its reason.
+        assert_eq!(status.reason, "admin request: test");
+        let phase = tokio::time::timeout(Duration::from_secs(5), handle.wait())
+            .await
+            .unwrap();
+        assert!(phase.is_force_closing(), "{phase}");
+        let status = client
+            .get_drain_status(GetDrainStatusRequest {})
+            .await
+            .unwrap();
+        assert_eq!(status.into_inner().phase(), drain_status::Phase::Stopped);
+    }
+
+    #[tokio::test]
+    async fn pins_serving_statuses() {
+        let (_, mut client) = serve(GracefulServer::builder()).await;
+        let err = client
+            .get_serving_statuses(GetServingStatusesRequest {})
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::FailedPrecondition, "{err}");
+
+        let server = GracefulServer::builder();
+        let (reporter, _) = health::health_reporter(server.lifecycle(), None, Default::default());
+        let (_, mut client) = serve(server.health_reporter(reporter)).await;
+        let serving = pb::ServiceStatus {
+            service: String::new(),
+            status: ServingStatus::Serving as i32,
+            pinned: false,
+        };
+        // The statuses follow the lifecycle in the background.
+        while client
+            .get_serving_statuses(GetServingStatusesRequest {})
+            .await
+            .unwrap()
+            .into_inner()
+            .services
+            != [serving.clone()]
+        {
+            tokio::task::yield_now().await;
+        }
+        let pin = |service: &str, status: ServingStatus| SetServingStatusRequest {
+            service: service.to_owned(),
+            status: status as i32,
+        };
+        let statuses = client
+            .set_serving_status(pin("", ServingStatus::NotServing))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            statuses.services,
+            [pb::ServiceStatus {
+                service: String::new(),
+                status: ServingStatus::NotServing as i32,
+                pinned: true,
+            }]
+        );
+        let err = client
+            .set_serving_status(pin("nope", ServingStatus::Serving))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::NotFound, "{err}");
+        let err = client
+            .set_serving_status(pin("", ServingStatus::Unspecified))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument, "{err}");
+
+        let statuses = client
+            .clear_serving_status(ClearServingStatusRequest {
+                service: String::new(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(statuses.services, [serving]);
+    }
+}
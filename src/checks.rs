@@ -0,0 +1,491 @@
+//! Health checks on the things a server depends on, so that its health status says whether it can actually serve.
+//!
+//! A [`Checker`] runs a [`HealthCheck`] every so often, and debounces the results: a healthy check has to fail
+//! `unhealthy_threshold` times in a row before the services it gates go `NOT_SERVING`, and then pass
+//! `healthy_threshold` times in a row before they are `SERVING` again. See [`crate::GracefulServer::health_check`].
+
+use std::{fmt, path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::Context as _;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::{health::ServiceStatuses, lifecycle::Phase};
+
+/// The shortest interval to run checks at, so that a zero interval does not turn into a busy loop.
+const MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Something the server needs in order to serve, like a database it talks to.
+#[tonic::async_trait]
+pub trait HealthCheck: fmt::Display + Send + Sync + 'static {
+    /// Whether the dependency is healthy right now. The error says why not.
+    async fn check(&self) -> anyhow::Result<()>;
+}
+
+/// Healthy if a TCP connection to `address` (`host:port`) can be established.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TcpCheck {
+    pub address: String,
+}
+
+#[tonic::async_trait]
+impl HealthCheck for TcpCheck {
+    async fn check(&self) -> anyhow::Result<()> {
+        tokio::net::TcpStream::connect(&self.address)
+            .await
+            .with_context(|| format!("failed to connect to {}", self.address))?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for TcpCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tcp:{}", self.address)
+    }
+}
+
+/// Healthy if there is a file at `path`, e.g. one that a sidecar writes once it is ready.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileCheck {
+    pub path: PathBuf,
+}
+
+#[tonic::async_trait]
+impl HealthCheck for FileCheck {
+    async fn check(&self) -> anyhow::Result<()> {
+        tokio::fs::metadata(&self.path)
+            .await
+            .with_context(|| format!("failed to stat {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for FileCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "file:{}", self.path.display())
+    }
+}
+
+/// Healthy if `command`, run with `sh -c`, exits with status 0. It is killed if the check times out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommandCheck {
+    pub command: String,
+}
+
+#[tonic::async_trait]
+impl HealthCheck for CommandCheck {
+    async fn check(&self) -> anyhow::Result<()> {
+        let output = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await
+            .with_context(|| format!("failed to run {}", self.command))?;
+        if output.status.success() {
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match stderr.trim() {
+            "" => anyhow::bail!("{} failed with {}", self.command, output.status),
+            stderr => anyhow::bail!("{} failed with {}: {stderr}", self.command, output.status),
+        }
+    }
+}
+
+impl fmt::Display for CommandCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cmd:{}", self.command)
+    }
+}
+
+/// One of the built-in checks, as written on the command line: `tcp:host:port`, `file:/path` or `cmd:command`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CheckSpec {
+    Tcp(TcpCheck),
+    File(FileCheck),
+    Command(CommandCheck),
+}
+
+impl CheckSpec {
+    pub fn into_check(self) -> Box<dyn HealthCheck> {
+        match self {
+            CheckSpec::Tcp(check) => Box::new(check),
+            CheckSpec::File(check) => Box::new(check),
+            CheckSpec::Command(check) => Box::new(check),
+        }
+    }
+}
+
+impl FromStr for CheckSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let spec = match s.split_once(':') {
+            Some(("tcp", address)) if !address.is_empty() => CheckSpec::Tcp(TcpCheck {
+                address: address.to_owned(),
+            }),
+            Some(("file", path)) if !path.is_empty() => {
+                CheckSpec::File(FileCheck { path: path.into() })
+            }
+            Some(("cmd", command)) if !command.is_empty() => CheckSpec::Command(CommandCheck {
+                command: command.to_owned(),
+            }),
+            _ => {
+                return Err(format!(
+                    "expected `tcp:host:port`, `file:/path` or `cmd:command`, got `{s}`"
+                ))
+            }
+        };
+        Ok(spec)
+    }
+}
+
+impl fmt::Display for CheckSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckSpec::Tcp(check) => check.fmt(f),
+            CheckSpec::File(check) => check.fmt(f),
+            CheckSpec::Command(check) => check.fmt(f),
+        }
+    }
+}
+
+/// Run `check` every `interval` (at least 1ms) until it passes, e.g. to wait for a dependency in a
+/// [`crate::GracefulServer::warmup`]. Never fails; bound it with [`crate::GracefulServer::startup_timeout`].
+pub async fn until_healthy(check: Box<dyn HealthCheck>, interval: Duration) -> anyhow::Result<()> {
+    let interval = interval.max(MIN_INTERVAL);
+    loop {
+        match check.check().await {
+            Ok(()) => {
+                info!(check = %check, "warmup check passed");
+                return Ok(());
+            }
+            Err(err) => info!(check = %check, "warmup check failed, retrying: {err:#}"),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Runs a [`HealthCheck`] on an interval, and takes the services it gates to `NOT_SERVING` while it fails.
+pub struct Checker {
+    check: Box<dyn HealthCheck>,
+    interval: Duration,
+    timeout: Duration,
+    healthy_threshold: u32,
+    unhealthy_threshold: u32,
+    services: Vec<String>,
+}
+
+impl Checker {
+    /// Run `check` every 5s, with a 1s timeout. It takes 3 failures in a row to go unhealthy, and 1 success to
+    /// recover. The very first result counts right away, so that the server does not claim to serve for several
+    /// intervals while a dependency is down.
+    pub fn new(check: impl HealthCheck) -> Self {
+        Self::boxed(Box::new(check))
+    }
+
+    pub fn boxed(check: Box<dyn HealthCheck>) -> Self {
+        Self {
+            check,
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(1),
+            healthy_threshold: 1,
+            unhealthy_threshold: 3,
+            services: Vec::new(),
+        }
+    }
+
+    /// How often to run the check. At least 1ms.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval.max(MIN_INTERVAL);
+        self
+    }
+
+    /// A check that takes longer than this has failed.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many successes in a row it takes to go from unhealthy to healthy. At least 1.
+    pub fn healthy_threshold(mut self, healthy_threshold: u32) -> Self {
+        self.healthy_threshold = healthy_threshold.max(1);
+        self
+    }
+
+    /// How many failures in a row it takes to go from healthy to unhealthy. At least 1.
+    pub fn unhealthy_threshold(mut self, unhealthy_threshold: u32) -> Self {
+        self.unhealthy_threshold = unhealthy_threshold.max(1);
+        self
+    }
+
+    /// The services (by full name, or the empty name for the server as a whole) that go `NOT_SERVING` while the
+    /// check fails. Empty, the default, means all of them.
+    pub fn services(mut self, services: Vec<String>) -> Self {
+        self.services = services;
+        self
+    }
+
+    pub(crate) fn gated_services(&self) -> &[String] {
+        &self.services
+    }
+
+    /// Run the check until the server stops, reporting its verdicts to `statuses` as check number `id`.
+    pub(crate) async fn run(
+        self,
+        id: usize,
+        statuses: ServiceStatuses,
+        mut phase: watch::Receiver<Phase>,
+    ) {
+        let name = self.check.to_string();
+        let mut healthy = None;
+        // Results in a row that disagree with `healthy`.
+        let mut streak = 0;
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = phase.wait_for(|phase| matches!(phase, Phase::Stopped { .. })) => return,
+            }
+            let result = match tokio::time::timeout(self.timeout, self.check.check()).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow::anyhow!(
+                    "timed out after {}ms",
+                    self.timeout.as_millis()
+                )),
+            };
+            let passed = result.is_ok();
+            streak = match healthy {
+                Some(healthy) if healthy != passed => streak + 1,
+                _ => 0,
+            };
+            let threshold = if passed {
+                self.healthy_threshold
+            } else {
+                self.unhealthy_threshold
+            };
+            let flipped = match healthy {
+                None => true,
+                Some(healthy) => healthy != passed && streak >= threshold,
+            };
+            if !flipped {
+                if let Err(err) = &result {
+                    if healthy == Some(true) {
+                        info!(check = %name, failures = streak, "health check failed: {err:#}");
+                    }
+                }
+                continue;
+            }
+            healthy = Some(passed);
+            streak = 0;
+            match result {
+                Ok(()) => info!(check = %name, "health check passing"),
+                Err(err) => warn!(check = %name, "health check failing: {err:#}"),
+            }
+            statuses.set_check(id, &self.services, passed).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use tokio::sync::{mpsc, Mutex};
+
+    use super::*;
+    use crate::lifecycle::{Lifecycle, ShutdownReason};
+
+    #[test]
+    fn parses_specs() {
+        assert_eq!(
+            "tcp:localhost:5432".parse(),
+            Ok(CheckSpec::Tcp(TcpCheck {
+                address: "localhost:5432".to_owned()
+            }))
+        );
+        assert_eq!(
+            "file:/run/ready".parse(),
+            Ok(CheckSpec::File(FileCheck {
+                path: "/run/ready".into()
+            }))
+        );
+        // Only the first colon separates the kind from the rest.
+        assert_eq!(
+            "cmd:test -e /a:b".parse(),
+            Ok(CheckSpec::Command(CommandCheck {
+                command: "test -e /a:b".to_owned()
+            }))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_specs() {
+        for s in [
+            "",
+            "tcp",
+            "tcp:",
+            "file:",
+            "cmd:",
+            "http://localhost",
+            "TCP:localhost:1",
+        ] {
+            assert!(s.parse::<CheckSpec>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in ["tcp:localhost:5432", "file:/run/ready", "cmd:true"] {
+            assert_eq!(s.parse::<CheckSpec>().unwrap().to_string(), s);
+        }
+    }
+
+    #[tokio::test]
+    async fn built_in_checks() {
+        let check = |s: &str| s.parse::<CheckSpec>().unwrap().into_check();
+        assert!(check("cmd:true").check().await.is_ok());
+        let err = check("cmd:echo oops >&2; exit 3")
+            .check()
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").ends_with(": oops"), "{err:#}");
+        assert!(check("file:/").check().await.is_ok());
+        assert!(check("file:/does/not/exist").check().await.is_err());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        assert!(check(&format!("tcp:{address}")).check().await.is_ok());
+        drop(listener);
+        assert!(check(&format!("tcp:{address}")).check().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn until_healthy_retries() {
+        let (check, _) = Scripted::new([false, false, true]);
+        until_healthy(Box::new(check), Duration::from_millis(1))
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn interval_is_at_least_a_millisecond() {
+        let checker = Checker::new(TcpCheck {
+            address: "localhost:1".to_owned(),
+        })
+        .interval(Duration::ZERO);
+        assert_eq!(checker.interval, MIN_INTERVAL);
+    }
+
+    /// A check that passes or fails as the test says, one verdict per call.
+    struct Scripted {
+        verdicts: Mutex<VecDeque<bool>>,
+        more: Mutex<mpsc::UnboundedReceiver<bool>>,
+        calls: mpsc::UnboundedSender<()>,
+    }
+
+    impl Scripted {
+        /// A check that returns `verdicts` first, and then whatever is sent on the returned channel. Each call is
+        /// announced on the channel, so the test knows when the previous verdict has been acted on.
+        fn new(
+            verdicts: impl IntoIterator<Item = bool>,
+        ) -> (
+            Self,
+            (mpsc::UnboundedSender<bool>, mpsc::UnboundedReceiver<()>),
+        ) {
+            let (more_tx, more) = mpsc::unbounded_channel();
+            let (calls, calls_rx) = mpsc::unbounded_channel();
+            let check = Self {
+                verdicts: Mutex::new(verdicts.into_iter().collect()),
+                more: Mutex::new(more),
+                calls,
+            };
+            (check, (more_tx, calls_rx))
+        }
+    }
+
+    impl fmt::Display for Scripted {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("scripted")
+        }
+    }
+
+    #[tonic::async_trait]
+    impl HealthCheck for Scripted {
+        async fn check(&self) -> anyhow::Result<()> {
+            let _ = self.calls.send(());
+            let next = self.verdicts.lock().await.pop_front();
+            let passed = match next {
+                Some(passed) => passed,
+                None => self.more.lock().await.recv().await.unwrap_or(true),
+            };
+            anyhow::ensure!(passed, "scripted failure");
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn checker_debounces() {
+        let (check, (verdicts, mut calls)) = Scripted::new([]);
+        let lifecycle = Lifecycle::new();
+        let (reporter, _) = tonic_health::server::health_reporter();
+        let statuses = ServiceStatuses::new(reporter, &["svc"], &[]).unwrap();
+        lifecycle.serving();
+        tokio::spawn(
+            statuses
+                .clone()
+                .follow(lifecycle.subscribe(), Duration::ZERO),
+        );
+        let serving = || async {
+            let statuses = statuses.statuses().await;
+            statuses
+                .iter()
+                .all(|(_, status, _)| *status == tonic_health::ServingStatus::Serving)
+        };
+        while !serving().await {
+            tokio::task::yield_now().await;
+        }
+
+        let checker = Checker::new(check)
+            .interval(Duration::from_millis(1))
+            .healthy_threshold(2)
+            .unhealthy_threshold(3);
+        let task = tokio::spawn(checker.run(0, statuses.clone(), lifecycle.subscribe()));
+        calls.recv().await.unwrap();
+        let steps = [
+            // The first verdict counts right away.
+            (false, false),
+            // Recovering takes two passes in a row.
+            (true, false),
+            (false, false),
+            (true, false),
+            (true, true),
+            // Going unhealthy takes three failures in a row.
+            (false, true),
+            (false, true),
+            (true, true),
+            (false, true),
+            (false, true),
+            (false, false),
+        ];
+        for (index, (passed, expected)) in steps.into_iter().enumerate() {
+            verdicts.send(passed).unwrap();
+            // The checker has acted on the verdict once it runs the check again.
+            calls.recv().await.unwrap();
+            assert_eq!(serving().await, expected, "step {index}");
+        }
+
+        // The checker stops with the server.
+        lifecycle.begin_shutdown(
+            ShutdownReason::Admin("test".to_owned()),
+            Duration::ZERO,
+            None,
+        );
+        lifecycle.stopped();
+        drop(verdicts);
+        task.await.unwrap();
+    }
+}
@@ -0,0 +1,370 @@
+//! The settings of the example server, as read from a TOML file.
+//!
+//! Every key matches a command-line flag (`lame_duck_ms` is `--lame-duck-ms`, `addresses` is the repeatable
+//! `--address`) and an environment variable (`TONIC_SHUTDOWN_LAME_DUCK_MS`). The binary layers them: flags override
+//! environment variables, which override the file, which overrides the defaults. A file only needs the keys it wants
+//! to change:
+//!
+//! ```toml
+//! addresses = ["[::]:50051", "unix:/run/tonic-shutdown.sock"]
+//! lame_duck_ms = 5000
+//! grace_period_ms = 30000
+//! ```
+
+use std::{
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Context as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    checks::CheckSpec,
+    exit::ExitCodes,
+    health::{HealthOverride, WatchClose},
+    listener::ListenAddr,
+};
+
+/// The example server's settings, see the flags of the same name for what each one does.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub addresses: Vec<ListenAddr>,
+    pub admin_address: Option<SocketAddr>,
+    pub metrics_address: Option<SocketAddr>,
+    pub metrics_final_scrape_ms: u64,
+    pub probes_address: Option<SocketAddr>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub client_ca: Option<PathBuf>,
+    pub lame_duck_ms: u64,
+    /// `None` waits forever.
+    pub grace_period_ms: Option<u64>,
+    /// `None` means no limit.
+    pub max_connections: Option<usize>,
+    /// `None` never ends `Watch` streams.
+    pub health_watch_close_delay_ms: Option<u64>,
+    pub health_watch_close_with: WatchClose,
+    pub health_drain_order: Vec<String>,
+    pub health_drain_step_ms: u64,
+    pub health_overrides: Vec<HealthOverride>,
+    pub health_checks: Vec<CheckSpec>,
+    pub health_check_interval_ms: u64,
+    pub health_check_timeout_ms: u64,
+    pub health_check_healthy_threshold: u32,
+    pub health_check_unhealthy_threshold: u32,
+    pub warmups: Vec<CheckSpec>,
+    /// `None` waits forever.
+    pub startup_timeout_ms: Option<u64>,
+    pub forced_exit_code: u8,
+    /// In `RUST_LOG` syntax. `None` falls back to `RUST_LOG`.
+    pub log_filter: Option<String>,
+    pub log_format: LogFormat,
+}
+
+/// How log records are printed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable, one line per record, with fields after the message.
+    #[default]
+    Text,
+    /// One JSON object per line, with fields as keys of their own, for log pipelines.
+    Json,
+    /// Like `Text`, but shorter.
+    Compact,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            "compact" => Ok(LogFormat::Compact),
+            _ => Err(format!("expected `text`, `json` or `compact`, got `{s}`")),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+            LogFormat::Compact => "compact",
+        })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            addresses: vec!["[::]:50051".parse().expect("valid address")],
+            admin_address: None,
+            metrics_address: None,
+            metrics_final_scrape_ms: 5000,
+            probes_address: None,
+            tls_cert: None,
+            tls_key: None,
+            client_ca: None,
+            lame_duck_ms: 0,
+            grace_period_ms: None,
+            max_connections: None,
+            health_watch_close_delay_ms: None,
+            health_watch_close_with: WatchClose::Ok,
+            health_drain_order: Vec::new(),
+            health_drain_step_ms: 0,
+            health_overrides: Vec::new(),
+            health_checks: Vec::new(),
+            health_check_interval_ms: 5000,
+            health_check_timeout_ms: 1000,
+            health_check_healthy_threshold: 1,
+            health_check_unhealthy_threshold: 3,
+            warmups: Vec::new(),
+            startup_timeout_ms: None,
+            forced_exit_code: ExitCodes::default().forced,
+            log_filter: None,
+            log_format: LogFormat::Text,
+        }
+    }
+}
+
+impl Config {
+    /// Read the settings in the TOML file at `path`, with defaults for the ones it leaves out.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// Reject settings that make no sense together.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(!self.addresses.is_empty(), "addresses must not be empty");
+        if let Some(grace_period_ms) = self.grace_period_ms {
+            // The grace period starts with the shutdown, so it has to cover the lame-duck period and then some.
+            anyhow::ensure!(
+                grace_period_ms >= self.lame_duck_ms,
+                "grace_period_ms ({grace_period_ms}) is shorter than lame_duck_ms ({}), so streams would be cut off \
+                 before the drain even starts",
+                self.lame_duck_ms
+            );
+        }
+        anyhow::ensure!(
+            self.max_connections != Some(0),
+            "max_connections must be at least 1"
+        );
+        anyhow::ensure!(
+            self.tls_cert.is_some() == self.tls_key.is_some(),
+            "tls_cert and tls_key must be set together"
+        );
+        anyhow::ensure!(
+            self.client_ca.is_none() || self.tls_cert.is_some(),
+            "client_ca requires tls_cert and tls_key"
+        );
+        anyhow::ensure!(
+            self.health_check_interval_ms > 0 && self.health_check_timeout_ms > 0,
+            "health_check_interval_ms and health_check_timeout_ms must be at least 1"
+        );
+        anyhow::ensure!(
+            self.health_check_healthy_threshold > 0 && self.health_check_unhealthy_threshold > 0,
+            "health_check_healthy_threshold and health_check_unhealthy_threshold must be at least 1"
+        );
+        let exit_codes = ExitCodes {
+            forced: self.forced_exit_code,
+            ..ExitCodes::default()
+        };
+        if let Some((_, meaning)) = exit_codes
+            .taken_by_others()
+            .into_iter()
+            .find(|(code, _)| *code == self.forced_exit_code)
+        {
+            anyhow::bail!(
+                "forced_exit_code must not be {}, which means {meaning}",
+                self.forced_exit_code
+            );
+        }
+        if let Some(log_filter) = &self.log_filter {
+            EnvFilter::try_new(log_filter).context("invalid log_filter")?;
+        }
+        Ok(())
+    }
+
+    /// The resolved settings as TOML, in the same format [`Config::from_file`] reads.
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+}
+
+/// (De)serialize through `Display` and `FromStr`, so that the file takes the same strings as the command line.
+macro_rules! serde_via_str {
+    ($($ty:ty),*) => {$(
+        impl Serialize for $ty {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $ty {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                <$ty>::from_str(&s).map_err(serde::de::Error::custom)
+            }
+        }
+    )*};
+}
+
+serde_via_str!(CheckSpec, HealthOverride, ListenAddr, LogFormat, WatchClose);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(config: Config) -> String {
+        format!("{:#}", config.validate().unwrap_err())
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        Config::default().validate().unwrap();
+    }
+
+    #[test]
+    fn needs_an_address() {
+        let config = Config {
+            addresses: Vec::new(),
+            ..Config::default()
+        };
+        assert_eq!(error(config), "addresses must not be empty");
+    }
+
+    #[test]
+    fn grace_period_covers_lame_duck() {
+        let config = Config {
+            lame_duck_ms: 5000,
+            grace_period_ms: Some(4999),
+            ..Config::default()
+        };
+        assert!(
+            error(config).starts_with("grace_period_ms (4999) is shorter than lame_duck_ms (5000)")
+        );
+        for grace_period_ms in [Some(5000), None] {
+            Config {
+                lame_duck_ms: 5000,
+                grace_period_ms,
+                ..Config::default()
+            }
+            .validate()
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn connection_limit_is_at_least_one() {
+        let config = Config {
+            max_connections: Some(0),
+            ..Config::default()
+        };
+        assert_eq!(error(config), "max_connections must be at least 1");
+    }
+
+    #[test]
+    fn tls_settings_go_together() {
+        let config = Config {
+            tls_cert: Some("cert.pem".into()),
+            ..Config::default()
+        };
+        assert_eq!(error(config), "tls_cert and tls_key must be set together");
+        let config = Config {
+            client_ca: Some("ca.pem".into()),
+            ..Config::default()
+        };
+        assert_eq!(error(config), "client_ca requires tls_cert and tls_key");
+    }
+
+    #[test]
+    fn check_interval_is_at_least_one() {
+        let config = Config {
+            health_check_interval_ms: 0,
+            ..Config::default()
+        };
+        assert_eq!(
+            error(config),
+            "health_check_interval_ms and health_check_timeout_ms must be at least 1"
+        );
+    }
+
+    #[test]
+    fn thresholds_are_at_least_one() {
+        let message =
+            "health_check_healthy_threshold and health_check_unhealthy_threshold must be at least 1";
+        let config = Config {
+            health_check_healthy_threshold: 0,
+            ..Config::default()
+        };
+        assert_eq!(error(config), message);
+        let config = Config {
+            health_check_unhealthy_threshold: 0,
+            ..Config::default()
+        };
+        assert_eq!(error(config), message);
+    }
+
+    #[test]
+    fn forced_exit_code_is_distinct() {
+        for code in [0, 1, 2, 3, 5, 101] {
+            let config = Config {
+                forced_exit_code: code,
+                ..Config::default()
+            };
+            assert!(
+                error(config).starts_with(&format!("forced_exit_code must not be {code}, ")),
+                "{code}"
+            );
+        }
+        Config {
+            forced_exit_code: 7,
+            ..Config::default()
+        }
+        .validate()
+        .unwrap();
+    }
+
+    #[test]
+    fn log_filter_must_parse() {
+        let config = Config {
+            log_filter: Some("info,[".to_owned()),
+            ..Config::default()
+        };
+        assert!(error(config).starts_with("invalid log_filter"));
+    }
+
+    #[test]
+    fn toml_round_trips() {
+        let config = Config {
+            addresses: vec![
+                "127.0.0.1:50051".parse().unwrap(),
+                "unix:/run/test.sock".parse().unwrap(),
+            ],
+            grace_period_ms: Some(30000),
+            max_connections: Some(100),
+            health_overrides: vec!["=not_serving".parse().unwrap()],
+            health_checks: vec!["tcp:localhost:5432".parse().unwrap()],
+            log_format: LogFormat::Json,
+            ..Config::default()
+        };
+        let parsed: Config = toml::from_str(&config.to_toml().unwrap()).unwrap();
+        assert_eq!(parsed, config);
+    }
+
+    #[test]
+    fn file_rejects_unknown_keys() {
+        assert!(toml::from_str::<Config>("lame_duck = 5").is_err());
+        let config: Config = toml::from_str("lame_duck_ms = 5").unwrap();
+        assert_eq!(config.lame_duck_ms, 5);
+    }
+}
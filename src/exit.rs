@@ -0,0 +1,106 @@
+use std::process::ExitCode;
+
+use crate::lifecycle::{Phase, ShutdownReason};
+
+/// The process exit code for each way a server can end, so that alerting can tell a clean drain from a forced one.
+///
+/// Codes 2 and 3 are taken: clap exits with 2 on invalid arguments, and a third shutdown signal aborts the process
+/// with [`crate::ABORT_EXIT_CODE`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExitCodes {
+    /// Every stream wrapped up before the grace period ran out. Always 0.
+    pub graceful: u8,
+    /// The grace period ran out (or a second signal cut it short) and we had to cut streams off.
+    pub forced: u8,
+    /// We never started serving, e.g. because the address was already in use, or a warmup hook failed.
+    pub bind_failure: u8,
+    /// The server failed while it was running.
+    pub serve_error: u8,
+    /// The server task panicked.
+    pub panic: u8,
+}
+
+impl Default for ExitCodes {
+    fn default() -> Self {
+        Self {
+            graceful: 0,
+            forced: 4,
+            bind_failure: 5,
+            serve_error: 1,
+            panic: 101,
+        }
+    }
+}
+
+impl ExitCodes {
+    /// Every code that means something other than a forced shutdown, with what it means. The forced code must not be
+    /// one of them, or alerting could not tell the two apart.
+    pub fn taken_by_others(&self) -> [(u8, &'static str); 6] {
+        [
+            (self.graceful, "a graceful shutdown"),
+            (self.serve_error, "a serve error"),
+            (2, "invalid arguments"),
+            (crate::ABORT_EXIT_CODE as u8, "an aborted shutdown"),
+            (self.bind_failure, "a failure to start"),
+            (self.panic, "a panic"),
+        ]
+    }
+
+    /// The exit code for a server that ended up in `phase`. Anything short of `Stopped` means we bailed out early,
+    /// which counts as a serve error.
+    pub fn for_phase(&self, phase: &Phase) -> ExitCode {
+        ExitCode::from(match phase {
+            Phase::Stopped {
+                reason: ShutdownReason::Panic(_),
+                ..
+            } => self.panic,
+            Phase::Stopped {
+                reason: ShutdownReason::Fatal(_),
+                ..
+            } => self.serve_error,
+            Phase::Stopped {
+                reason: ShutdownReason::Startup(_),
+                ..
+            } => self.bind_failure,
+            Phase::Stopped { forced: true, .. } => self.forced,
+            Phase::Stopped { forced: false, .. } => self.graceful,
+            _ => self.serve_error,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal::Signal;
+
+    fn stopped(reason: ShutdownReason, forced: bool) -> Phase {
+        Phase::Stopped { reason, forced }
+    }
+
+    #[test]
+    fn maps_outcomes_to_codes() {
+        let codes = ExitCodes {
+            forced: 42,
+            ..ExitCodes::default()
+        };
+        let signal = || ShutdownReason::Signal(Signal::Term);
+        let cases = [
+            (stopped(signal(), false), 0),
+            (stopped(ShutdownReason::Upgrade(1), false), 0),
+            (stopped(signal(), true), 42),
+            (stopped(ShutdownReason::Fatal("bind".to_owned()), false), 1),
+            // A panic or a failed start wins over the forced flag.
+            (stopped(ShutdownReason::Panic("boom".to_owned()), true), 101),
+            (
+                stopped(ShutdownReason::Startup("warmup".to_owned()), true),
+                5,
+            ),
+            // The server should have stopped by the time we exit.
+            (Phase::Serving, 1),
+        ];
+        for (phase, code) in cases {
+            assert_eq!(codes.for_phase(&phase), ExitCode::from(code), "{phase}");
+        }
+    }
+}
@@ -0,0 +1,571 @@
+use std::{collections::BTreeMap, fmt, pin::Pin, str::FromStr, sync::Arc, time::Duration};
+
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tonic::{Request, Response, Status};
+use tonic_health::{
+    pb::{
+        health_check_response::ServingStatus,
+        health_client::HealthClient,
+        health_server::{Health, HealthServer},
+        HealthCheckRequest, HealthCheckResponse,
+    },
+    server::HealthReporter,
+};
+
+use crate::lifecycle::{Lifecycle, Phase};
+
+/// How a `Watch` stream ends once the server is shutting down.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WatchClose {
+    /// End the stream with an `OK` status.
+    #[default]
+    Ok,
+    /// End the stream with an `UNAVAILABLE` status.
+    Unavailable,
+}
+
+impl FromStr for WatchClose {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ok" => Ok(WatchClose::Ok),
+            "unavailable" => Ok(WatchClose::Unavailable),
+            _ => Err(format!("expected `ok` or `unavailable`, got `{s}`")),
+        }
+    }
+}
+
+impl fmt::Display for WatchClose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            WatchClose::Ok => "ok",
+            WatchClose::Unavailable => "unavailable",
+        })
+    }
+}
+
+/// A service's health status pinned in the configuration, as written on the command line: `service=serving` or
+/// `service=not_serving`, with an empty service name for the server as a whole. See
+/// [`ServiceStatuses::set_override`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HealthOverride {
+    pub service: String,
+    pub status: tonic_health::ServingStatus,
+}
+
+impl FromStr for HealthOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (service, status) = match s.rsplit_once('=') {
+            Some((service, "serving")) => (service, tonic_health::ServingStatus::Serving),
+            Some((service, "not_serving")) => (service, tonic_health::ServingStatus::NotServing),
+            _ => {
+                return Err(format!(
+                    "expected `service=serving` or `service=not_serving`, got `{s}`"
+                ))
+            }
+        };
+        Ok(HealthOverride {
+            service: service.to_owned(),
+            status,
+        })
+    }
+}
+
+impl fmt::Display for HealthOverride {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self.status {
+            tonic_health::ServingStatus::Serving => "serving",
+            tonic_health::ServingStatus::NotServing => "not_serving",
+            tonic_health::ServingStatus::Unknown => "unknown",
+        };
+        write!(f, "{}={status}", self.service)
+    }
+}
+
+/// Like [`tonic_health::server::health_reporter`], except that `Watch` streams end by themselves once `lifecycle`
+/// starts shutting down, instead of blocking the drain forever.
+///
+/// After shutdown begins, each `Watch` stream is guaranteed to see `NOT_SERVING`, and is then closed `close_delay`
+/// later as `close_with` says. With a `close_delay` of `None`, streams are never closed, just like with `tonic_health`.
+pub fn health_reporter(
+    lifecycle: &Lifecycle,
+    close_delay: Option<Duration>,
+    close_with: WatchClose,
+) -> (HealthReporter, HealthServer<impl Health>) {
+    let (reporter, inner) = tonic_health::server::health_reporter();
+    let service = HealthService {
+        // Talking to tonic_health's service through an in-process client lets us reuse its implementation, and with
+        // it the statuses that `reporter` updates.
+        inner: HealthClient::new(inner),
+        phase: lifecycle.subscribe(),
+        close_delay,
+        close_with,
+    };
+    (reporter, HealthServer::new(service))
+}
+
+struct HealthService<T> {
+    inner: HealthClient<T>,
+    phase: watch::Receiver<Phase>,
+    close_delay: Option<Duration>,
+    close_with: WatchClose,
+}
+
+#[tonic::async_trait]
+impl<T> Health for HealthService<T>
+where
+    T: tonic::client::GrpcService<tonic::body::BoxBody> + Clone + Send + Sync + 'static,
+    T::Future: Send,
+    T::ResponseBody: tonic::codegen::Body<Data = bytes::Bytes> + Send + 'static,
+    <T::ResponseBody as tonic::codegen::Body>::Error: Into<tonic::codegen::StdError> + Send,
+{
+    async fn check(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<HealthCheckResponse>, Status> {
+        self.inner.clone().check(request.into_inner()).await
+    }
+
+    type WatchStream =
+        Pin<Box<dyn Stream<Item = Result<HealthCheckResponse, Status>> + Send + 'static>>;
+
+    async fn watch(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let mut inner = self
+            .inner
+            .clone()
+            .watch(request.into_inner())
+            .await?
+            .into_inner();
+        let Some(close_delay) = self.close_delay else {
+            return Ok(Response::new(Box::pin(inner) as Self::WatchStream));
+        };
+
+        let (tx, rx) = mpsc::channel(1);
+        let mut phase = self.phase.clone();
+        let close_with = self.close_with;
+        tokio::spawn(async move {
+            // Also resolves if the lifecycle goes away, since there is no server left to watch then.
+            let close = async {
+                loop {
+                    if phase.wait_for(Phase::is_shutting_down).await.is_err() {
+                        return;
+                    }
+                    // Start over if the shutdown is aborted before we get around to closing the stream.
+                    tokio::select! {
+                        () = tokio::time::sleep(close_delay) => return,
+                        aborted = phase.wait_for(|phase| !phase.is_shutting_down()) => {
+                            if aborted.is_err() {
+                                return;
+                            }
+                        },
+                    }
+                }
+            };
+            tokio::pin!(close);
+
+            let mut last_status = None;
+            loop {
+                tokio::select! {
+                    msg = inner.message() => match msg {
+                        Ok(Some(msg)) => {
+                            last_status = Some(msg.status());
+                            if tx.send(Ok(msg)).await.is_err() {
+                                return;
+                            }
+                        }
+                        Ok(None) => return,
+                        Err(status) => {
+                            let _ = tx.send(Err(status)).await;
+                            return;
+                        }
+                    },
+                    () = &mut close => break,
+                    () = tx.closed() => return,
+                }
+            }
+
+            if last_status != Some(ServingStatus::NotServing) {
+                let msg = HealthCheckResponse {
+                    status: ServingStatus::NotServing as i32,
+                };
+                if tx.send(Ok(msg)).await.is_err() {
+                    return;
+                }
+            }
+            if close_with == WatchClose::Unavailable {
+                let _ = tx
+                    .send(Err(Status::unavailable("server shutting down")))
+                    .await;
+            }
+        });
+        Ok(Response::new(
+            Box::pin(ReceiverStream::new(rx)) as Self::WatchStream
+        ))
+    }
+}
+
+/// The health status of every service a [`crate::GracefulServer`] serves, kept in step with its lifecycle.
+///
+/// Every service, and the server as a whole (the empty service name), gets its own entry. They are `NOT_SERVING` while
+/// the server starts, all become `SERVING` once it serves, and go back to `NOT_SERVING` one after the other, in drain
+/// order, when a shutdown begins. If the shutdown is aborted, they are all `SERVING` again.
+///
+/// Failing [`crate::checks`] take the services they gate to `NOT_SERVING` until they pass again.
+///
+/// Operators can pin a service's status with [`ServiceStatuses::set_override`]. The pinned status holds until it is
+/// cleared, whatever the checks say, except that a shutdown still takes the service to `NOT_SERVING`: a draining
+/// server never claims to serve.
+#[derive(Clone)]
+pub struct ServiceStatuses {
+    inner: Arc<Mutex<Statuses>>,
+}
+
+struct Statuses {
+    reporter: HealthReporter,
+    /// Every service, in drain order.
+    services: Vec<String>,
+    /// Whether the server is serving, i.e. what services that are neither pinned nor drained report.
+    serving: bool,
+    /// How many of `services` the current drain has taken to `NOT_SERVING`.
+    drained: usize,
+    overrides: BTreeMap<String, tonic_health::ServingStatus>,
+    /// The services gated by each failing check, by check number. Empty means all of them.
+    failing_checks: BTreeMap<usize, Vec<String>>,
+}
+
+impl Statuses {
+    fn index(&self, service: &str) -> anyhow::Result<usize> {
+        self.services
+            .iter()
+            .position(|known| known == service)
+            .ok_or_else(|| anyhow::anyhow!("no such service: {service:?}"))
+    }
+
+    fn status(&self, index: usize) -> tonic_health::ServingStatus {
+        if index < self.drained {
+            return tonic_health::ServingStatus::NotServing;
+        }
+        let service = &self.services[index];
+        let failing = self
+            .failing_checks
+            .values()
+            .any(|gated| gated.is_empty() || gated.contains(service));
+        match self.overrides.get(service) {
+            Some(status) => *status,
+            None if self.serving && !failing => tonic_health::ServingStatus::Serving,
+            None => tonic_health::ServingStatus::NotServing,
+        }
+    }
+
+    async fn report(&mut self, index: usize) {
+        let status = self.status(index);
+        self.reporter
+            .set_service_status(&self.services[index], status)
+            .await;
+    }
+
+    async fn report_all(&mut self) {
+        for index in 0..self.services.len() {
+            self.report(index).await;
+        }
+    }
+}
+
+impl ServiceStatuses {
+    /// Report the status of each of `services` (and of the empty service name) through `reporter`.
+    ///
+    /// They drain in the order `drain_order` lists them, then the rest in the order of `services`. The empty service
+    /// name drains last, unless `drain_order` lists it. Names in `drain_order` that are not in `services` are an error.
+    pub fn new(
+        reporter: HealthReporter,
+        services: &[&str],
+        drain_order: &[String],
+    ) -> anyhow::Result<Self> {
+        let mut ordered = Vec::with_capacity(services.len() + 1);
+        for service in drain_order {
+            anyhow::ensure!(
+                service.is_empty() || services.contains(&service.as_str()),
+                "cannot order the health status of {service}, which is not served"
+            );
+            if !ordered.contains(service) {
+                ordered.push(service.clone());
+            }
+        }
+        for service in services.iter().copied().chain([""]) {
+            if !ordered.iter().any(|ordered| ordered == service) {
+                ordered.push(service.to_owned());
+            }
+        }
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Statuses {
+                reporter,
+                services: ordered,
+                serving: false,
+                drained: 0,
+                overrides: BTreeMap::new(),
+                failing_checks: BTreeMap::new(),
+            })),
+        })
+    }
+
+    /// Every service's current status, in drain order, and whether it is pinned.
+    pub async fn statuses(&self) -> Vec<(String, tonic_health::ServingStatus, bool)> {
+        let inner = self.inner.lock().await;
+        (0..inner.services.len())
+            .map(|index| {
+                let service = &inner.services[index];
+                let pinned = inner.overrides.contains_key(service);
+                (service.clone(), inner.status(index), pinned)
+            })
+            .collect()
+    }
+
+    /// Pin `service`'s status to `status` until [`ServiceStatuses::clear_override`]. Fails if there is no such service.
+    pub async fn set_override(
+        &self,
+        service: &str,
+        status: tonic_health::ServingStatus,
+    ) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().await;
+        let index = inner.index(service)?;
+        inner.overrides.insert(service.to_owned(), status);
+        inner.report(index).await;
+        Ok(())
+    }
+
+    /// Let `service`'s status follow the server's again. Fails if there is no such service.
+    pub async fn clear_override(&self, service: &str) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().await;
+        let index = inner.index(service)?;
+        inner.overrides.remove(service);
+        inner.report(index).await;
+        Ok(())
+    }
+
+    /// Report every service's status, `NOT_SERVING` until [`ServiceStatuses::follow`] sees the server serve.
+    pub(crate) async fn report_all(&self) {
+        self.inner.lock().await.report_all().await;
+    }
+
+    /// Fail unless every one of `services` has a status, for checks that gate them.
+    pub(crate) async fn ensure_known(&self, services: &[String]) -> anyhow::Result<()> {
+        let inner = self.inner.lock().await;
+        for service in services {
+            inner.index(service)?;
+        }
+        Ok(())
+    }
+
+    /// Forget check number `id`, e.g. because it was replaced, as if it had passed.
+    pub(crate) async fn remove_check(&self, id: usize) {
+        let mut inner = self.inner.lock().await;
+        if inner.failing_checks.remove(&id).is_some() {
+            inner.report_all().await;
+        }
+    }
+
+    /// Record the verdict of check number `id`, which gates `services` (all of them if empty).
+    pub(crate) async fn set_check(&self, id: usize, services: &[String], healthy: bool) {
+        let mut inner = self.inner.lock().await;
+        if healthy {
+            inner.failing_checks.remove(&id);
+        } else {
+            inner.failing_checks.insert(id, services.to_vec());
+        }
+        inner.report_all().await;
+    }
+
+    /// Follow `phase` until the lifecycle goes away, leaving `step` between services as they drain.
+    pub(crate) async fn follow(self, mut phase: watch::Receiver<Phase>, step: Duration) {
+        loop {
+            if phase
+                .wait_for(|phase| *phase == Phase::Serving)
+                .await
+                .is_err()
+            {
+                return;
+            }
+            {
+                let mut inner = self.inner.lock().await;
+                inner.serving = true;
+                inner.drained = 0;
+                inner.report_all().await;
+            }
+            if phase.wait_for(Phase::is_shutting_down).await.is_err() {
+                return;
+            }
+            let services = self.inner.lock().await.services.len();
+            for index in 0..services {
+                if index > 0 && !step.is_zero() {
+                    tokio::select! {
+                        () = tokio::time::sleep(step) => {}
+                        _ = phase.wait_for(|phase| !phase.is_shutting_down()) => break,
+                    }
+                }
+                let mut inner = self.inner.lock().await;
+                inner.drained = index + 1;
+                inner.report(index).await;
+            }
+            // Start over if the shutdown is aborted.
+            if phase
+                .wait_for(|phase| !phase.is_shutting_down())
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn watch(health: HealthServer<impl Health>) -> tonic::Streaming<HealthCheckResponse> {
+        HealthClient::new(health)
+            .watch(HealthCheckRequest {
+                service: String::new(),
+            })
+            .await
+            .unwrap()
+            .into_inner()
+    }
+
+    #[tokio::test]
+    async fn watch_ends_when_the_lifecycle_goes_away() {
+        let lifecycle = Lifecycle::new();
+        let (_reporter, health) =
+            health_reporter(&lifecycle, Some(Duration::from_secs(60)), WatchClose::Ok);
+        let mut watch = watch(health).await;
+        assert_eq!(
+            watch.message().await.unwrap().unwrap().status(),
+            ServingStatus::Serving
+        );
+        drop(lifecycle);
+        let ended = tokio::time::timeout(Duration::from_secs(5), async {
+            assert_eq!(
+                watch.message().await.unwrap().unwrap().status(),
+                ServingStatus::NotServing
+            );
+            assert!(watch.message().await.unwrap().is_none());
+        });
+        ended.await.expect("the Watch outlived the lifecycle");
+    }
+
+    fn shut_down(lifecycle: &Lifecycle) {
+        let reason = crate::lifecycle::ShutdownReason::Admin("test".to_owned());
+        lifecycle.begin_shutdown(reason, Duration::from_secs(60), None);
+    }
+
+    #[tokio::test]
+    async fn watch_says_not_serving_then_closes() {
+        for (close_with, code) in [
+            (WatchClose::Ok, None),
+            (WatchClose::Unavailable, Some(tonic::Code::Unavailable)),
+        ] {
+            let lifecycle = Lifecycle::new();
+            lifecycle.serving();
+            let (_reporter, health) =
+                health_reporter(&lifecycle, Some(Duration::from_millis(50)), close_with);
+            let mut watch = watch(health).await;
+            assert_eq!(
+                watch.message().await.unwrap().unwrap().status(),
+                ServingStatus::Serving
+            );
+            shut_down(&lifecycle);
+            assert_eq!(
+                watch.message().await.unwrap().unwrap().status(),
+                ServingStatus::NotServing
+            );
+            let end = watch.message().await;
+            assert_eq!(end.as_ref().err().map(Status::code), code, "{end:?}");
+            if code.is_none() {
+                assert!(end.unwrap().is_none());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn watch_survives_an_aborted_shutdown() {
+        let lifecycle = Lifecycle::new();
+        lifecycle.serving();
+        let (_reporter, health) =
+            health_reporter(&lifecycle, Some(Duration::from_millis(100)), WatchClose::Ok);
+        let mut watch = watch(health).await;
+        watch.message().await.unwrap();
+        shut_down(&lifecycle);
+        assert!(lifecycle.abort_shutdown());
+        let message = tokio::time::timeout(Duration::from_millis(300), watch.message()).await;
+        assert!(message.is_err(), "the Watch ended: {message:?}");
+    }
+
+    #[tokio::test]
+    async fn watch_stays_open_without_a_close_delay() {
+        let lifecycle = Lifecycle::new();
+        lifecycle.serving();
+        let (_reporter, health) = health_reporter(&lifecycle, None, WatchClose::Ok);
+        let mut watch = watch(health).await;
+        watch.message().await.unwrap();
+        shut_down(&lifecycle);
+        let message = tokio::time::timeout(Duration::from_millis(100), watch.message()).await;
+        assert!(message.is_err(), "the Watch ended: {message:?}");
+    }
+
+    #[tokio::test]
+    async fn starts_not_serving() {
+        let (reporter, server) = tonic_health::server::health_reporter();
+        let statuses = ServiceStatuses::new(reporter, &["helloworld.Greeter"], &[]).unwrap();
+        statuses.report_all().await;
+        let mut client = HealthClient::new(server);
+        for service in ["", "helloworld.Greeter"] {
+            let response = client
+                .check(HealthCheckRequest {
+                    service: service.to_owned(),
+                })
+                .await
+                .unwrap();
+            assert_eq!(
+                response.into_inner().status(),
+                ServingStatus::NotServing,
+                "{service:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn parses_overrides() {
+        assert_eq!(
+            "helloworld.Greeter=not_serving".parse(),
+            Ok(HealthOverride {
+                service: "helloworld.Greeter".to_owned(),
+                status: tonic_health::ServingStatus::NotServing,
+            })
+        );
+        assert_eq!(
+            "=serving".parse(),
+            Ok(HealthOverride {
+                service: String::new(),
+                status: tonic_health::ServingStatus::Serving,
+            })
+        );
+        for s in [
+            "",
+            "helloworld.Greeter",
+            "helloworld.Greeter=unknown",
+            "=SERVING",
+        ] {
+            assert!(s.parse::<HealthOverride>().is_err(), "{s}");
+        }
+        for s in ["helloworld.Greeter=not_serving", "=serving"] {
+            assert_eq!(s.parse::<HealthOverride>().unwrap().to_string(), s);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! A minimal HTTP/1.1 server, for the endpoints that scrapers and probes expect to reach without gRPC.
+
+use std::{convert::Infallible, future::Future};
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{body::Incoming as Body, server::conn::http1, service::service_fn};
+use hyper_util::rt::TokioIo;
+use tokio_stream::StreamExt;
+use tracing::debug;
+
+use crate::listener::{Incoming, Listener};
+
+pub(crate) type Request = http::Request<Body>;
+pub(crate) type Response = http::Response<Full<Bytes>>;
+
+/// Answer every request on `listener` with `handler`, until `shutdown` resolves. Connections that are open by then
+/// are left to finish on their own.
+pub(crate) async fn serve<H, F>(listener: Listener, handler: H, shutdown: impl Future<Output = ()>)
+where
+    H: Fn(Request) -> F + Clone + Send + Sync + 'static,
+    F: Future<Output = Response> + Send + 'static,
+{
+    let mut incoming = Incoming::new(vec![listener]);
+    tokio::pin!(shutdown);
+    loop {
+        let conn = tokio::select! {
+            conn = incoming.next() => conn,
+            () = &mut shutdown => return,
+        };
+        let Some(conn) = conn else {
+            return;
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let response = handler(req);
+                async move { Ok::<_, Infallible>(response.await) }
+            });
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(conn), service)
+                .await
+            {
+                debug!("HTTP connection failed: {err}");
+            }
+        });
+    }
+}
+
+/// A plain-text response.
+pub(crate) fn text(status: http::StatusCode, body: impl Into<Bytes>) -> Response {
+    let mut response = http::Response::new(Full::new(body.into()));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    response
+}
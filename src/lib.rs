@@ -0,0 +1,28 @@
+//! Graceful (and, when that fails, semi-graceful) shutdown for tonic servers.
+//!
+//! Build a [`GracefulServer`], add your services, and [`serve`](GracefulServer::serve) it. The returned
+//! [`ServerHandle`] lets you trigger a shutdown, watch the server's [`Phase`], and wait for it to stop. Handlers can
+//! find out that the server is draining through the [`ShutdownToken`] attached to every request.
+
+pub mod admin;
+pub mod checks;
+pub mod config;
+pub mod exit;
+pub mod health;
+mod http_server;
+pub mod lifecycle;
+pub mod listener;
+pub mod metrics;
+mod probes;
+pub mod registry;
+mod server;
+pub mod signal;
+pub mod systemd;
+pub mod tls;
+pub mod token;
+pub mod upgrade;
+
+pub use exit::ExitCodes;
+pub use lifecycle::{Lifecycle, Phase, ShutdownReason};
+pub use server::{GracefulServer, ServerHandle, ABORT_EXIT_CODE};
+pub use token::ShutdownToken;
@@ -0,0 +1,630 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{sync::watch, time::Instant};
+use tracing::{error, info, warn};
+
+use crate::{registry::Registry, signal::Signal};
+
+/// Why the server started shutting down.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ShutdownReason {
+    /// The process received a shutdown signal.
+    Signal(Signal),
+    /// Something asked for the drain programmatically, e.g. through [`crate::ServerHandle::shutdown`].
+    Admin(String),
+    /// The server hit an error it cannot recover from.
+    Fatal(String),
+    /// The server task panicked.
+    Panic(String),
+    /// A new copy of the server took over our listeners (see [`crate::upgrade`]); this is its pid.
+    Upgrade(u32),
+    /// A warmup hook failed or timed out (see [`crate::GracefulServer::warmup`]), so the server never got to serve.
+    Startup(String),
+}
+
+impl fmt::Display for ShutdownReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShutdownReason::Signal(sig) => write!(f, "recv {sig}"),
+            ShutdownReason::Admin(msg) => write!(f, "admin request: {msg}"),
+            ShutdownReason::Fatal(msg) => write!(f, "fatal error: {msg}"),
+            ShutdownReason::Panic(msg) => write!(f, "panic: {msg}"),
+            ShutdownReason::Upgrade(pid) => write!(f, "handed over to pid {pid}"),
+            ShutdownReason::Startup(msg) => write!(f, "startup failed: {msg}"),
+        }
+    }
+}
+
+/// Where the server is in its lifecycle. Phases only ever move forward, with one exception: a shutdown can be aborted
+/// during the lame-duck period, since the listener is still open then, as long as the server was serving when it
+/// began.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Starting,
+    Serving,
+    /// We report `NOT_SERVING` so that load balancers stop routing to us, but keep accepting and serving
+    /// connections until `until`. The grace period is already ticking.
+    LameDuck {
+        reason: ShutdownReason,
+        until: Instant,
+        deadline: Option<Instant>,
+        /// Whether the server was serving when the shutdown began. One that interrupted startup cannot be aborted,
+        /// since the server never got through its warmups.
+        abortable: bool,
+    },
+    /// We have stopped accepting new connections and are waiting for live streams to wrap up. If there is a
+    /// deadline, streams still open when it passes get cut off.
+    Draining {
+        reason: ShutdownReason,
+        deadline: Option<Instant>,
+    },
+    /// We gave up waiting and are tearing down whatever is left.
+    ForceClosing {
+        reason: ShutdownReason,
+    },
+    Stopped {
+        reason: ShutdownReason,
+        forced: bool,
+    },
+}
+
+impl Phase {
+    /// Every phase's [`Phase::name`], in lifecycle order.
+    pub const NAMES: [&'static str; 6] = [
+        "starting",
+        "serving",
+        "lame_duck",
+        "draining",
+        "force_closing",
+        "stopped",
+    ];
+
+    /// The phase without its details, e.g. `lame_duck`, for metrics and structured logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Starting => "starting",
+            Phase::Serving => "serving",
+            Phase::LameDuck { .. } => "lame_duck",
+            Phase::Draining { .. } => "draining",
+            Phase::ForceClosing { .. } => "force_closing",
+            Phase::Stopped { .. } => "stopped",
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        !matches!(self, Phase::Starting | Phase::Serving)
+    }
+
+    /// Whether we are past the lame-duck period, i.e. the listener should be closed.
+    pub fn is_draining(&self) -> bool {
+        matches!(
+            self,
+            Phase::Draining { .. } | Phase::ForceClosing { .. } | Phase::Stopped { .. }
+        )
+    }
+
+    /// When streams that are still open will be cut off, if ever.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self {
+            Phase::LameDuck { deadline, .. } | Phase::Draining { deadline, .. } => *deadline,
+            _ => None,
+        }
+    }
+
+    pub fn is_force_closing(&self) -> bool {
+        matches!(
+            self,
+            Phase::ForceClosing { .. } | Phase::Stopped { forced: true, .. }
+        )
+    }
+
+    pub fn reason(&self) -> Option<&ShutdownReason> {
+        match self {
+            Phase::Starting | Phase::Serving => None,
+            Phase::LameDuck { reason, .. }
+            | Phase::Draining { reason, .. }
+            | Phase::ForceClosing { reason }
+            | Phase::Stopped { reason, .. } => Some(reason),
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Starting => f.write_str("starting"),
+            Phase::Serving => f.write_str("serving"),
+            Phase::LameDuck { reason, .. } => write!(f, "lame duck ({reason})"),
+            Phase::Draining { reason, .. } => write!(f, "draining ({reason})"),
+            Phase::ForceClosing { reason } => write!(f, "force closing ({reason})"),
+            Phase::Stopped {
+                reason,
+                forced: false,
+            } => write!(f, "stopped gracefully ({reason})"),
+            Phase::Stopped {
+                reason,
+                forced: true,
+            } => write!(f, "stopped forcefully ({reason})"),
+        }
+    }
+}
+
+type Hook = Box<dyn Fn(&Phase, &Phase) + Send + Sync>;
+
+/// The single source of truth for the server's lifecycle.
+///
+/// Async code can watch the current phase via [`Lifecycle::subscribe`]. Watch receivers only ever see the latest
+/// value, so anything that needs to observe every transition should register a hook with
+/// [`Lifecycle::on_transition`] instead.
+#[derive(Clone)]
+pub struct Lifecycle {
+    tx: Arc<watch::Sender<Phase>>,
+    hooks: Arc<Mutex<Vec<Hook>>>,
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lifecycle {
+    pub fn new() -> Self {
+        let (tx, _) = watch::channel(Phase::Starting);
+        Self {
+            tx: Arc::new(tx),
+            hooks: Default::default(),
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.tx.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Phase> {
+        self.tx.subscribe()
+    }
+
+    /// Register a hook that is called with `(previous, next)` on every transition. Hooks run synchronously on
+    /// whichever task caused the transition, so they should be quick, and must not transition the lifecycle
+    /// themselves.
+    pub fn on_transition(&self, hook: impl Fn(&Phase, &Phase) + Send + Sync + 'static) {
+        self.hooks.lock().unwrap().push(Box::new(hook));
+    }
+
+    /// Starting -> Serving.
+    pub fn serving(&self) -> bool {
+        self.transition(|phase| match phase {
+            Phase::Starting => Some(Phase::Serving),
+            _ => None,
+        })
+    }
+
+    /// Starting/Serving -> LameDuck, or straight to Draining if `lame_duck` is zero. `grace_period` bounds how long
+    /// the whole shutdown may take, lame-duck period included; `None` waits forever.
+    pub fn begin_shutdown(
+        &self,
+        reason: ShutdownReason,
+        lame_duck: Duration,
+        grace_period: Option<Duration>,
+    ) -> bool {
+        self.transition(|phase| match phase {
+            Phase::Starting | Phase::Serving => {
+                let abortable = *phase == Phase::Serving;
+                let now = Instant::now();
+                let deadline = grace_period.map(|grace_period| now + grace_period);
+                Some(if lame_duck.is_zero() {
+                    Phase::Draining { reason, deadline }
+                } else {
+                    Phase::LameDuck {
+                        reason,
+                        until: now + lame_duck,
+                        deadline,
+                        abortable,
+                    }
+                })
+            }
+            _ => None,
+        })
+    }
+
+    /// LameDuck -> Serving, unless the shutdown began before the server was serving.
+    pub fn abort_shutdown(&self) -> bool {
+        self.transition(|phase| match phase {
+            Phase::LameDuck {
+                abortable: true, ..
+            } => Some(Phase::Serving),
+            _ => None,
+        })
+    }
+
+    /// LameDuck -> Draining, keeping the deadline we already had.
+    pub fn drain(&self) -> bool {
+        self.transition(|phase| match phase {
+            Phase::LameDuck {
+                reason, deadline, ..
+            } => Some(Phase::Draining {
+                reason: reason.clone(),
+                deadline: *deadline,
+            }),
+            _ => None,
+        })
+    }
+
+    /// LameDuck/Draining -> ForceClosing, keeping the reason the shutdown started with.
+    pub fn force_close(&self) -> bool {
+        self.transition(|phase| match phase {
+            Phase::LameDuck { reason, .. } | Phase::Draining { reason, .. } => {
+                Some(Phase::ForceClosing {
+                    reason: reason.clone(),
+                })
+            }
+            _ => None,
+        })
+    }
+
+    /// Record that the server has finished, carrying over the reason for the shutdown.
+    pub fn stopped(&self) -> bool {
+        self.transition(|phase| match phase {
+            Phase::Stopped { .. } => None,
+            phase => Some(Phase::Stopped {
+                reason: phase.reason().cloned().unwrap_or_else(|| {
+                    ShutdownReason::Fatal("server exited unexpectedly".to_owned())
+                }),
+                forced: matches!(phase, Phase::ForceClosing { .. }),
+            }),
+        })
+    }
+
+    /// Record that the server died (with a [`ShutdownReason::Fatal`] or [`ShutdownReason::Panic`]), regardless of
+    /// what phase it was in.
+    pub fn failed(&self, reason: ShutdownReason) -> bool {
+        self.transition(|phase| match phase {
+            Phase::Stopped { .. } => None,
+            phase => Some(Phase::Stopped {
+                reason,
+                forced: matches!(phase, Phase::ForceClosing { .. }),
+            }),
+        })
+    }
+
+    fn transition(&self, f: impl FnOnce(&Phase) -> Option<Phase>) -> bool {
+        // Holding the hook lock across the whole transition keeps hooks seeing transitions in the order they happen.
+        let hooks = self.hooks.lock().unwrap();
+        let mut changed = None;
+        self.tx.send_if_modified(|phase| match f(phase) {
+            Some(next) => {
+                changed = Some((std::mem::replace(phase, next.clone()), next));
+                true
+            }
+            None => false,
+        });
+        let Some((prev, next)) = changed else {
+            return false;
+        };
+        for hook in hooks.iter() {
+            hook(&prev, &next);
+        }
+        true
+    }
+}
+
+/// Log every transition, with the `phase`, the shutdown `reason`, `remaining_streams` and `grace_remaining_ms` as
+/// fields of their own. [`crate::GracefulServer`] registers this on every lifecycle it creates.
+pub fn log_transition(prev: &Phase, next: &Phase, registry: &Registry) {
+    let phase = next.name();
+    let remaining_streams = registry.streams().len();
+    match next {
+        Phase::Starting => {}
+        Phase::Serving => match prev {
+            Phase::LameDuck { .. } => info!(phase, "shutdown aborted, serving again"),
+            _ => info!(phase, "server is serving"),
+        },
+        Phase::LameDuck {
+            reason,
+            until,
+            deadline,
+            ..
+        } => {
+            let lame_duck_ms = remaining_ms(*until);
+            let grace_remaining_ms = deadline.map(remaining_ms);
+            info!(
+                phase,
+                %reason,
+                lame_duck_ms,
+                grace_remaining_ms,
+                remaining_streams,
+                "entering lame duck: reporting NOT_SERVING but still accepting connections"
+            );
+        }
+        Phase::Draining { reason, deadline } => {
+            let message = match prev {
+                Phase::LameDuck { .. } => "lame duck period over, draining traffic",
+                _ => "draining traffic",
+            };
+            match deadline {
+                Some(deadline) => info!(
+                    phase,
+                    %reason,
+                    grace_remaining_ms = remaining_ms(*deadline),
+                    remaining_streams,
+                    "{message}: waiting for clients to disconnect"
+                ),
+                None => info!(
+                    phase,
+                    %reason,
+                    remaining_streams,
+                    "{message}: waiting forever for clients to disconnect"
+                ),
+            }
+        }
+        Phase::ForceClosing { reason } => match prev.deadline() {
+            Some(deadline) if deadline <= Instant::now() => warn!(
+                phase,
+                %reason,
+                remaining_streams,
+                "grace period exhausted, forcefully shutting down connections"
+            ),
+            _ => warn!(
+                phase,
+                %reason,
+                remaining_streams,
+                "forcefully shutting down connections"
+            ),
+        },
+        Phase::Stopped {
+            reason: ShutdownReason::Fatal(msg),
+            ..
+        } => error!(phase, reason = %msg, "server stopped"),
+        Phase::Stopped {
+            reason: ShutdownReason::Panic(msg),
+            ..
+        } => error!(phase, reason = %msg, "server panicked"),
+        Phase::Stopped {
+            reason: ShutdownReason::Startup(msg),
+            ..
+        } => error!(phase, reason = %msg, "server failed to start, exiting"),
+        Phase::Stopped {
+            reason,
+            forced: false,
+        } => info!(phase, %reason, "all clients gracefully disconnected, exiting"),
+        Phase::Stopped {
+            reason,
+            forced: true,
+        } => warn!(
+            phase,
+            %reason,
+            remaining_streams,
+            "exiting after forcefully closing connections"
+        ),
+    }
+}
+
+fn remaining_ms(deadline: Instant) -> u64 {
+    deadline
+        .saturating_duration_since(Instant::now())
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admin() -> ShutdownReason {
+        ShutdownReason::Admin("test".to_owned())
+    }
+
+    #[test]
+    fn starts_then_serves_once() {
+        let lifecycle = Lifecycle::new();
+        assert_eq!(lifecycle.phase(), Phase::Starting);
+        assert!(lifecycle.serving());
+        assert_eq!(lifecycle.phase(), Phase::Serving);
+        assert!(!lifecycle.serving());
+    }
+
+    #[test]
+    fn lame_duck_then_drain_keeps_reason_and_deadline() {
+        let lifecycle = Lifecycle::new();
+        lifecycle.serving();
+        let grace_period = Duration::from_secs(10);
+        assert!(lifecycle.begin_shutdown(admin(), Duration::from_secs(5), Some(grace_period)));
+        let Phase::LameDuck {
+            reason,
+            until,
+            deadline: Some(deadline),
+            abortable: true,
+        } = lifecycle.phase()
+        else {
+            panic!(
+                "expected a lame duck with a deadline, got {}",
+                lifecycle.phase()
+            );
+        };
+        assert_eq!(reason, admin());
+        assert!(until < deadline);
+        assert!(lifecycle.phase().is_shutting_down());
+        assert!(!lifecycle.phase().is_draining());
+
+        // A second shutdown request changes nothing.
+        assert!(!lifecycle.begin_shutdown(ShutdownReason::Upgrade(1), Duration::ZERO, None));
+        assert_eq!(lifecycle.phase().reason(), Some(&admin()));
+
+        assert!(lifecycle.drain());
+        assert_eq!(
+            lifecycle.phase(),
+            Phase::Draining {
+                reason: admin(),
+                deadline: Some(deadline),
+            }
+        );
+        assert!(lifecycle.phase().is_draining());
+        assert!(!lifecycle.drain());
+        assert!(!lifecycle.abort_shutdown());
+    }
+
+    #[test]
+    fn zero_lame_duck_drains_right_away() {
+        let lifecycle = Lifecycle::new();
+        lifecycle.serving();
+        assert!(lifecycle.begin_shutdown(admin(), Duration::ZERO, None));
+        assert_eq!(
+            lifecycle.phase(),
+            Phase::Draining {
+                reason: admin(),
+                deadline: None,
+            }
+        );
+    }
+
+    #[test]
+    fn abort_only_during_lame_duck() {
+        let lifecycle = Lifecycle::new();
+        lifecycle.serving();
+        assert!(!lifecycle.abort_shutdown());
+        lifecycle.begin_shutdown(admin(), Duration::from_secs(5), None);
+        assert!(lifecycle.abort_shutdown());
+        assert_eq!(lifecycle.phase(), Phase::Serving);
+
+        // Once aborted, the server can shut down again.
+        assert!(lifecycle.begin_shutdown(admin(), Duration::ZERO, None));
+        assert!(!lifecycle.abort_shutdown());
+    }
+
+    #[test]
+    fn no_abort_before_serving() {
+        let lifecycle = Lifecycle::new();
+        assert!(lifecycle.begin_shutdown(admin(), Duration::from_secs(5), None));
+        assert!(matches!(
+            lifecycle.phase(),
+            Phase::LameDuck {
+                abortable: false,
+                ..
+            }
+        ));
+        // Serving again would skip the warmups.
+        assert!(!lifecycle.abort_shutdown());
+        assert!(lifecycle.phase().is_shutting_down());
+    }
+
+    #[test]
+    fn force_close_then_stop() {
+        let lifecycle = Lifecycle::new();
+        lifecycle.serving();
+        assert!(!lifecycle.force_close());
+        lifecycle.begin_shutdown(admin(), Duration::ZERO, None);
+        assert!(lifecycle.force_close());
+        assert_eq!(lifecycle.phase(), Phase::ForceClosing { reason: admin() });
+        assert!(lifecycle.phase().is_force_closing());
+        assert!(lifecycle.stopped());
+        assert_eq!(
+            lifecycle.phase(),
+            Phase::Stopped {
+                reason: admin(),
+                forced: true,
+            }
+        );
+        assert!(!lifecycle.stopped());
+        assert!(!lifecycle.failed(ShutdownReason::Fatal("late".to_owned())));
+    }
+
+    #[test]
+    fn graceful_stop() {
+        let lifecycle = Lifecycle::new();
+        lifecycle.serving();
+        lifecycle.begin_shutdown(admin(), Duration::ZERO, None);
+        assert!(lifecycle.stopped());
+        assert_eq!(
+            lifecycle.phase(),
+            Phase::Stopped {
+                reason: admin(),
+                forced: false,
+            }
+        );
+        assert!(!lifecycle.phase().is_force_closing());
+    }
+
+    #[test]
+    fn stopping_without_a_shutdown_is_fatal() {
+        let lifecycle = Lifecycle::new();
+        lifecycle.serving();
+        assert!(lifecycle.stopped());
+        assert!(matches!(
+            lifecycle.phase(),
+            Phase::Stopped {
+                reason: ShutdownReason::Fatal(_),
+                forced: false,
+            }
+        ));
+    }
+
+    #[test]
+    fn failed_from_any_phase() {
+        let lifecycle = Lifecycle::new();
+        let reason = ShutdownReason::Panic("boom".to_owned());
+        assert!(lifecycle.failed(reason.clone()));
+        assert_eq!(
+            lifecycle.phase(),
+            Phase::Stopped {
+                reason,
+                forced: false,
+            }
+        );
+    }
+
+    #[test]
+    fn hooks_see_every_transition_in_order() {
+        let lifecycle = Lifecycle::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        lifecycle.on_transition({
+            let seen = seen.clone();
+            move |prev, next| seen.lock().unwrap().push((prev.name(), next.name()))
+        });
+        lifecycle.serving();
+        lifecycle.begin_shutdown(admin(), Duration::ZERO, None);
+        lifecycle.force_close();
+        // Not a transition, so no hook call.
+        lifecycle.drain();
+        lifecycle.stopped();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                ("starting", "serving"),
+                ("serving", "draining"),
+                ("draining", "force_closing"),
+                ("force_closing", "stopped"),
+            ]
+        );
+    }
+
+    #[test]
+    fn names_are_in_lifecycle_order() {
+        let reason = admin();
+        let phases = [
+            Phase::Starting,
+            Phase::Serving,
+            Phase::LameDuck {
+                reason: reason.clone(),
+                until: Instant::now(),
+                deadline: None,
+                abortable: true,
+            },
+            Phase::Draining {
+                reason: reason.clone(),
+                deadline: None,
+            },
+            Phase::ForceClosing {
+                reason: reason.clone(),
+            },
+            Phase::Stopped {
+                reason,
+                forced: false,
+            },
+        ];
+        assert_eq!(phases.map(|phase| phase.name()), Phase::NAMES);
+    }
+}
@@ -0,0 +1,533 @@
+use std::{
+    env, fmt, fs,
+    future::Future,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::fs::FileTypeExt,
+    },
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    time::Sleep,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_stream::Stream;
+use tonic::transport::server::{Connected, TcpConnectInfo};
+use tracing::{debug, warn};
+
+/// The first file descriptor passed by systemd's socket activation protocol.
+const SD_LISTEN_FDS_START: RawFd = 3;
+/// How long to stop accepting on a listener after an error that is not about the connection itself, such as running
+/// out of file descriptors, which would otherwise keep failing in a tight loop.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A listening socket to serve on: bound by us, or inherited from whoever started us.
+pub struct Listener {
+    inner: Inner,
+    /// The socket file to remove when the server stops, for Unix sockets that we are responsible for.
+    socket_path: Option<PathBuf>,
+}
+
+enum Inner {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Where a [`Listener`] accepts connections.
+///
+/// Parses from `host:port` (e.g. `[::]:50051` or `localhost:50051`) or `unix:/path/to.sock`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    /// `None` for unnamed and abstract sockets.
+    Unix(Option<PathBuf>),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{addr}"),
+            ListenAddr::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            ListenAddr::Unix(None) => f.write_str("unix:(unnamed)"),
+        }
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("expected a path after `unix:`".to_owned());
+            }
+            return Ok(ListenAddr::Unix(Some(path.into())));
+        }
+        if let Ok(addr) = s.parse() {
+            return Ok(ListenAddr::Tcp(addr));
+        }
+        // Not an IP address, so try resolving the host name.
+        s.to_socket_addrs()
+            .map_err(|err| format!("expected `host:port` or `unix:/path`, got `{s}`: {err}"))?
+            .next()
+            .map(ListenAddr::Tcp)
+            .ok_or_else(|| format!("`{s}` did not resolve to any address"))
+    }
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ListenAddr::Tcp(addr)
+    }
+}
+
+impl Listener {
+    /// Bind to `address`. A Unix socket file left behind by a server that is no longer running is replaced, and the
+    /// socket file is removed again when the server stops.
+    pub async fn bind(address: &ListenAddr) -> io::Result<Self> {
+        match address {
+            ListenAddr::Tcp(addr) => Ok(Self {
+                inner: Inner::Tcp(TcpListener::bind(addr).await?),
+                socket_path: None,
+            }),
+            ListenAddr::Unix(Some(path)) => Ok(Self {
+                inner: Inner::Unix(bind_unix(path)?),
+                socket_path: Some(path.clone()),
+            }),
+            ListenAddr::Unix(None) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot bind an unnamed Unix socket",
+            )),
+        }
+    }
+
+    /// Take over the listening socket `fd`, e.g. one passed down by a supervisor. It is marked close-on-exec.
+    ///
+    /// If `fd` turns out not to be a TCP or Unix listening socket, it is left alone and an error is returned.
+    ///
+    /// # Safety
+    ///
+    /// If `fd` is open, nothing else may own or close it.
+    pub unsafe fn from_raw_fd(fd: RawFd) -> io::Result<Self> {
+        // getsockopt also fails if `fd` is not open, or not a socket.
+        if sockopt(fd, libc::SO_ACCEPTCONN)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("fd {fd} is not a listening socket"),
+            ));
+        }
+        let domain = sockopt(fd, libc::SO_DOMAIN)?;
+        if ![libc::AF_INET, libc::AF_INET6, libc::AF_UNIX].contains(&domain) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("fd {fd} has unsupported socket domain {domain}"),
+            ));
+        }
+        let fd = OwnedFd::from_raw_fd(fd);
+        if libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let inner = if domain == libc::AF_UNIX {
+            let listener = std::os::unix::net::UnixListener::from(fd);
+            listener.set_nonblocking(true)?;
+            Inner::Unix(UnixListener::from_std(listener)?)
+        } else {
+            let listener = std::net::TcpListener::from(fd);
+            listener.set_nonblocking(true)?;
+            Inner::Tcp(TcpListener::from_std(listener)?)
+        };
+        Ok(Self {
+            inner,
+            socket_path: None,
+        })
+    }
+
+    /// The listeners passed by systemd socket activation (`LISTEN_FDS`), with their names from `LISTEN_FDNAMES`.
+    /// Empty if we were not socket activated, or if the file descriptors are meant for another process.
+    pub fn from_systemd() -> io::Result<Vec<(String, Listener)>> {
+        let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok());
+        if pid != Some(std::process::id()) {
+            return Ok(Vec::new());
+        }
+        let count: RawFd = match env::var("LISTEN_FDS").map(|fds| fds.parse()) {
+            Ok(Ok(count)) => count,
+            _ => return Ok(Vec::new()),
+        };
+        let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+        let mut names = names.split(':');
+        (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+            .map(|fd| {
+                let name = names
+                    .next()
+                    .filter(|name| !name.is_empty())
+                    .unwrap_or("unknown");
+                // SAFETY: LISTEN_PID says these file descriptors were passed to us, and nothing else claims them.
+                Ok((name.to_owned(), unsafe { Listener::from_raw_fd(fd)? }))
+            })
+            .collect()
+    }
+
+    pub fn local_addr(&self) -> io::Result<ListenAddr> {
+        match &self.inner {
+            Inner::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+            Inner::Unix(listener) => listener
+                .local_addr()
+                .map(|addr| ListenAddr::Unix(addr.as_pathname().map(Into::into))),
+        }
+    }
+
+    /// Make the server remove this listener's socket file when it stops, as if it had bound the socket itself.
+    pub fn remove_on_stop(mut self) -> io::Result<Self> {
+        if let ListenAddr::Unix(path) = self.local_addr()? {
+            self.socket_path = path;
+        }
+        Ok(self)
+    }
+
+    /// The socket file to remove when the server stops, see [`Listener::remove_on_stop`].
+    pub fn socket_path(&self) -> Option<&Path> {
+        self.socket_path.as_deref()
+    }
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<Conn>> {
+        match &self.inner {
+            Inner::Tcp(listener) => listener.poll_accept(cx).map(|accepted| {
+                let (stream, _) = accepted?;
+                // The connection still works without it, so this is no reason to turn it away.
+                if let Err(err) = stream.set_nodelay(true) {
+                    debug!("failed to set TCP_NODELAY: {err}");
+                }
+                Ok(Conn::Tcp(stream))
+            }),
+            Inner::Unix(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, _)| Conn::Unix(stream)),
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.inner {
+            Inner::Tcp(listener) => listener.as_raw_fd(),
+            Inner::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// Bind a Unix socket at `path`, replacing a stale socket file if there is one. A socket file is stale if nothing
+/// accepts connections on it any more.
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+            let is_socket = fs::symlink_metadata(path)?.file_type().is_socket();
+            let refused = std::os::unix::net::UnixStream::connect(path)
+                .is_err_and(|err| err.kind() == io::ErrorKind::ConnectionRefused);
+            if !(is_socket && refused) {
+                return Err(err);
+            }
+            warn!("removing stale socket file {}", path.display());
+            fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        bound => bound,
+    }
+}
+
+fn sockopt(fd: RawFd, opt: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `value` and `len` are valid for writes of an int, which is what these options return.
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            opt,
+            (&mut value as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+/// Accepts connections from several listeners at once.
+///
+/// Accept errors are handled here rather than yielded: tonic would only log them at trace level and poll again, which
+/// spins on errors that do not go away by themselves. Errors that only failed the connection being accepted are logged
+/// and skipped; any other error pauses that listener for [`ACCEPT_ERROR_BACKOFF`], while the others keep accepting.
+pub(crate) struct Incoming {
+    listeners: Vec<Listener>,
+    /// For each listener, when it may accept again after an error.
+    backoff: Vec<Option<Pin<Box<Sleep>>>>,
+    /// Where to start polling next time, so that a busy listener cannot starve the others.
+    next: usize,
+}
+
+impl Incoming {
+    pub(crate) fn new(listeners: Vec<Listener>) -> Self {
+        Self {
+            backoff: listeners.iter().map(|_| None).collect(),
+            listeners,
+            next: 0,
+        }
+    }
+
+    /// Accept a connection from the listener at `index`, unless it is backing off.
+    fn poll_listener(&mut self, index: usize, cx: &mut Context<'_>) -> Poll<Conn> {
+        if let Some(backoff) = &mut self.backoff[index] {
+            ready!(backoff.as_mut().poll(cx));
+            self.backoff[index] = None;
+        }
+        loop {
+            match ready!(self.listeners[index].poll_accept(cx)) {
+                Ok(conn) => return Poll::Ready(conn),
+                Err(err) if is_connection_error(&err) => {
+                    debug!("failed to accept connection: {err}");
+                }
+                Err(err) => {
+                    let listener = self.listeners[index]
+                        .local_addr()
+                        .map_or_else(|_| "listener".to_owned(), |addr| addr.to_string());
+                    warn!(
+                        "failed to accept connection on {listener}, retrying in {}s: {err}",
+                        ACCEPT_ERROR_BACKOFF.as_secs()
+                    );
+                    let mut backoff = Box::pin(tokio::time::sleep(ACCEPT_ERROR_BACKOFF));
+                    // Registers for the wakeup once it is over.
+                    let _ = backoff.as_mut().poll(cx);
+                    self.backoff[index] = Some(backoff);
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+impl Stream for Incoming {
+    type Item = Conn;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let count = self.listeners.len();
+        for i in 0..count {
+            let index = (self.next + i) % count;
+            if let Poll::Ready(conn) = self.poll_listener(index, cx) {
+                self.next = (index + 1) % count;
+                return Poll::Ready(Some(conn));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// Whether `err` only failed the one connection being accepted, rather than the listener.
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// A connection accepted from a [`Listener`].
+pub enum Conn {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    /// A connection that completed the TLS handshake, see [`crate::tls`].
+    Tls(Box<TlsStream<Conn>>),
+}
+
+impl Conn {
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Conn::Tcp(stream) => stream.peer_addr().ok(),
+            Conn::Unix(_) => None,
+            Conn::Tls(stream) => stream.get_ref().0.peer_addr(),
+        }
+    }
+}
+
+impl Connected for Conn {
+    // Unix connections get an empty `TcpConnectInfo`, so that handlers (and `Request::remote_addr`) only ever have to
+    // look for one type.
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        match self {
+            Conn::Tcp(stream) => stream.connect_info(),
+            Conn::Unix(_) => TcpConnectInfo {
+                local_addr: None,
+                remote_addr: None,
+            },
+            Conn::Tls(stream) => stream.get_ref().0.connect_info(),
+        }
+    }
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Conn::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Conn::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            Conn::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Conn::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Conn::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            Conn::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Conn::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Conn::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            Conn::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Conn::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Conn::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            Conn::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Conn::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Conn::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Conn::Tls(stream) => Pin::new(stream.as_mut()).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Conn::Tcp(stream) => stream.is_write_vectored(),
+            Conn::Unix(stream) => stream.is_write_vectored(),
+            Conn::Tls(stream) => stream.is_write_vectored(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_stream::StreamExt as _;
+
+    use super::*;
+
+    #[test]
+    fn parses_socket_addresses() {
+        assert_eq!(
+            "127.0.0.1:50051".parse(),
+            Ok(ListenAddr::Tcp("127.0.0.1:50051".parse().unwrap()))
+        );
+        assert_eq!(
+            "[::]:50051".parse(),
+            Ok(ListenAddr::Tcp("[::]:50051".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn resolves_host_names() {
+        let Ok(ListenAddr::Tcp(addr)) = "localhost:50051".parse() else {
+            panic!("localhost did not resolve");
+        };
+        assert!(addr.ip().is_loopback());
+        assert_eq!(addr.port(), 50051);
+    }
+
+    #[test]
+    fn parses_unix_paths() {
+        assert_eq!(
+            "unix:/run/test.sock".parse(),
+            Ok(ListenAddr::Unix(Some("/run/test.sock".into())))
+        );
+        assert_eq!(
+            "unix:".parse::<ListenAddr>(),
+            Err("expected a path after `unix:`".to_owned())
+        );
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        for s in ["", "50051", "localhost", "[::]", "127.0.0.1:port"] {
+            assert!(s.parse::<ListenAddr>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in ["127.0.0.1:50051", "[::1]:1", "unix:/run/test.sock"] {
+            assert_eq!(s.parse::<ListenAddr>().unwrap().to_string(), s);
+        }
+        assert_eq!(ListenAddr::Unix(None).to_string(), "unix:(unnamed)");
+    }
+
+    #[tokio::test]
+    async fn binds_and_replaces_stale_unix_sockets() {
+        let path = env::temp_dir().join(format!("listener-{}.sock", std::process::id()));
+        let address = ListenAddr::Unix(Some(path.clone()));
+        // A socket file that nothing listens on any more, as left behind by a crash.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listener = Listener::bind(&address).await.unwrap();
+        assert!(UnixStream::connect(&path).await.is_ok());
+        drop(listener);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn backs_off_a_failing_listener_and_serves_the_others() {
+        let address = ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 0)));
+        let failing = Listener::bind(&address).await.unwrap();
+        // Every accept on a listening socket that was shut down fails with EINVAL, and it stays readable.
+        // SAFETY: shutdown only changes the state of the socket, which `failing` keeps open.
+        assert_eq!(
+            unsafe { libc::shutdown(failing.as_raw_fd(), libc::SHUT_RDWR) },
+            0
+        );
+        let working = Listener::bind(&address).await.unwrap();
+        let ListenAddr::Tcp(working_addr) = working.local_addr().unwrap() else {
+            unreachable!()
+        };
+        let mut incoming = Incoming::new(vec![failing, working]);
+
+        let _client = TcpStream::connect(working_addr).await.unwrap();
+        let conn = tokio::time::timeout(Duration::from_secs(5), incoming.next())
+            .await
+            .expect("the failing listener held up the working one");
+        assert!(conn.is_some());
+        assert!(incoming.backoff[0].is_some());
+        assert!(incoming.backoff[1].is_none());
+    }
+}
@@ -1,77 +1,833 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    net::SocketAddr,
+    os::fd::RawFd,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+    time::Duration,
+};
 
 use clap::Parser;
-use tonic::transport::Server;
-use tracing::{info, warn};
+use tonic_shutdown_example::{
+    admin,
+    checks::{self, CheckSpec, Checker},
+    config::{Config, LogFormat},
+    health::{self, HealthOverride, WatchClose},
+    listener::{ListenAddr, Listener},
+    systemd::Notifier,
+    tls::TlsConfig,
+    upgrade::Handover,
+    ExitCodes, GracefulServer, ServerHandle,
+};
+use tracing::{error, info, warn};
+use tracing_subscriber::{
+    layer::SubscriberExt as _, reload, util::SubscriberInitExt as _, EnvFilter, Layer as _,
+};
+
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0    every stream wrapped up within the grace period
+  1    the server failed while it was running
+  2    invalid arguments or configuration
+  3    aborted by a third shutdown signal
+  4    streams were cut off when the grace period ran out (see --forced-exit-code)
+  5    the server failed to start, e.g. because it could not bind --address or --warmup timed out
+  101  the server panicked";
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    let config = resolve_config(args.config.as_deref(), args.settings.clone());
+    // Even an invalid config gets its error logged, in the default format.
+    let log_filter = match &config {
+        Ok(config) => init_logging(config.log_format, config.log_filter.as_deref()),
+        Err(_) => init_logging(LogFormat::default(), None),
+    };
+    let config = match config {
+        Ok(config) => config,
+        Err(err) => {
+            error!("invalid configuration: {err:#}");
+            return ExitCode::from(2);
+        }
+    };
+    if let Some(Command::Config {
+        command: ConfigCommand::Check,
+    }) = args.command
+    {
+        return match config.to_toml() {
+            Ok(toml) => {
+                print!("{toml}");
+                ExitCode::SUCCESS
+            }
+            Err(err) => {
+                error!("failed to print configuration: {err:#}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+    let exit_codes = ExitCodes {
+        forced: config.forced_exit_code,
+        ..ExitCodes::default()
+    };
 
-    let Args {
-        address,
-        grace_period_ms,
-    } = Args::parse();
-    let address: SocketAddr = address.parse()?;
-
-    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
-    let reflection_service = tonic_reflection::server::Builder::configure()
-        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
-        .build_v1()?;
-
-    let (tx, mut shutdown) = tokio::sync::watch::channel(false);
-    tokio::spawn(async move {
-        let _ = tokio::signal::ctrl_c().await;
-        info!("recv SIGINT, latching shutdown signal");
-        tx.send_replace(true);
-    });
+    let reloader = Reloader {
+        path: args.config,
+        settings: args.settings,
+        log_filter,
+        current: tokio::sync::Mutex::new(config.clone()),
+    };
+    let server = match start(config, args.listen_fds, reloader).await {
+        Ok(server) => server,
+        Err(err) => {
+            error!("failed to start server: {err:#}");
+            return ExitCode::from(exit_codes.bind_failure);
+        }
+    };
+    for addr in server.local_addrs() {
+        info!("server listening on {addr}");
+    }
+    if let Some(admin_addr) = server.admin_addr() {
+        info!("admin service listening on {admin_addr}");
+    }
+    if let Some(metrics_addr) = server.metrics_addr() {
+        info!("metrics listening on http://{metrics_addr}/metrics");
+    }
+    if let Some(probes_addr) = server.probes_addr() {
+        info!("probes listening on http://{probes_addr}/readyz");
+    }
 
-    // This future will resolve when the server shuts down organically (either via a graceful serve_with_shutdown
-    // or by encountering an error).
-    let organic = tokio::spawn({
-        let mut shutdown = shutdown.clone();
-        info!("server listening on {}", address);
-        Server::builder()
-            .add_service(health_service)
-            .add_service(reflection_service)
-            .serve_with_shutdown(address, async move {
-                let _ = shutdown.wait_for(|&is_shutdown| is_shutdown).await;
-                info!("marking as unhealthy to discourage clients");
-                health_reporter
-                    .set_service_status("", tonic_health::ServingStatus::NotServing)
-                    .await;
-                info!("no longer accepting new connections");
-            })
-    });
+    exit_codes.for_phase(&server.wait().await)
+}
 
-    // This future will resolve after the process receives a SIGINT and the grace period has expired.
-    // When it resolves, we need to shut down ungracefully.
-    let ungraceful = async move {
-        let _ = shutdown.wait_for(|&is_shutdown| is_shutdown).await;
-        if let Some(grace_period_ms) = grace_period_ms {
-            info!("waiting up to {grace_period_ms}ms for clients to disconnect",);
-            tokio::time::sleep(Duration::from_millis(grace_period_ms)).await;
-        } else {
-            info!("waiting forever for clients to disconnect");
-            let () = std::future::pending().await;
+async fn start(
+    config: Config,
+    listen_fds: Vec<RawFd>,
+    reloader: Reloader,
+) -> anyhow::Result<ServerHandle> {
+    let checkers = checkers(&config);
+    let Config {
+        addresses,
+        admin_address,
+        metrics_address,
+        metrics_final_scrape_ms,
+        probes_address,
+        tls_cert,
+        tls_key,
+        client_ca,
+        lame_duck_ms,
+        grace_period_ms,
+        max_connections,
+        health_watch_close_delay_ms,
+        health_watch_close_with,
+        health_drain_order,
+        health_drain_step_ms,
+        health_overrides,
+        health_checks: _,
+        health_check_interval_ms,
+        health_check_timeout_ms: _,
+        health_check_healthy_threshold: _,
+        health_check_unhealthy_threshold: _,
+        warmups,
+        startup_timeout_ms,
+        forced_exit_code: _,
+        log_filter: _,
+        log_format: _,
+    } = config;
+
+    let mut listeners = Vec::new();
+    let mut admin_listener = None;
+    let mut metrics_listener = None;
+    let mut probes_listener = None;
+    // An upgrade hands over all the listeners the old process had, however it got them.
+    let mut handover = Handover::from_env()?;
+    match &mut handover {
+        Some(handover) => {
+            info!("taking over listeners from the previous process");
+            for (name, listener) in std::mem::take(&mut handover.listeners) {
+                match name.as_str() {
+                    "admin" => admin_listener = Some(listener),
+                    "metrics" => metrics_listener = Some(listener),
+                    "probes" => probes_listener = Some(listener),
+                    _ => listeners.push(listener),
+                }
+            }
         }
-    };
+        None => {
+            for (name, listener) in Listener::from_systemd()? {
+                info!("using listener {name} from socket activation");
+                listeners.push(listener);
+            }
+            for fd in listen_fds {
+                // SAFETY: whoever passed --listen-fd handed the file descriptor over to us.
+                listeners.push(
+                    unsafe { Listener::from_raw_fd(fd) }
+                        .map_err(|err| anyhow::anyhow!("--listen-fd {fd}: {err}"))?,
+                );
+            }
+        }
+    }
 
-    tokio::select! {
-        r = organic => {
-            r??; // if we hit any kind of organic error with the server, bubble that up
-            info!("all clients gracefully disconnected, exiting");
-        },
-        _ = ungraceful => warn!("grace period exhausted, forcefully shutting down connections"),
+    let server = GracefulServer::builder();
+    let (health_reporter, health_service) = health::health_reporter(
+        server.lifecycle(),
+        health_watch_close_delay_ms.map(Duration::from_millis),
+        health_watch_close_with,
+    );
+    let mut reflection_service = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET);
+    // With a dedicated admin listener, the admin service has its own reflection service.
+    let dedicated_admin = admin_address.is_some() || admin_listener.is_some();
+    if !dedicated_admin {
+        reflection_service =
+            reflection_service.register_encoded_file_descriptor_set(admin::pb::FILE_DESCRIPTOR_SET);
+    }
+    let reflection_service = reflection_service.build_v1()?;
+
+    let mut server = checkers
+        .into_iter()
+        .fold(server, GracefulServer::health_check);
+    server = health_overrides
+        .into_iter()
+        .fold(server, |server, health_override| {
+            server.health_override(health_override.service, health_override.status)
+        });
+    server = warmups.into_iter().fold(server, |server, check| {
+        server.warmup(checks::until_healthy(
+            check.into_check(),
+            Duration::from_millis(health_check_interval_ms),
+        ))
+    });
+    server = server
+        .startup_timeout(startup_timeout_ms.map(Duration::from_millis))
+        .lame_duck(Duration::from_millis(lame_duck_ms))
+        .grace_period(grace_period_ms.map(Duration::from_millis))
+        .max_connections(max_connections)
+        .health_reporter(health_reporter)
+        .health_drain_order(health_drain_order)
+        .health_drain_step(Duration::from_millis(health_drain_step_ms))
+        .shutdown_on_signals()
+        .on_reload({
+            let reloader = Arc::new(reloader);
+            move |handle| {
+                let reloader = reloader.clone();
+                let handle = handle.clone();
+                tokio::spawn(async move { reloader.reload(&handle).await });
+            }
+        })
+        .admin_service(admin_address)
+        .metrics(metrics_address)
+        .final_scrape_timeout(Duration::from_millis(metrics_final_scrape_ms))
+        .probes(probes_address)
+        .tls(
+            tls_cert
+                .zip(tls_key)
+                .map(|(cert, key)| TlsConfig::new(cert, key).client_ca(client_ca)),
+        )
+        .notify_systemd(Notifier::from_env()?)
+        .register_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)?
+        .register_file_descriptor_set(admin::pb::FILE_DESCRIPTOR_SET)?
+        .add_service(health_service.clone())
+        .add_service(reflection_service)
+        // Probes on the admin listener keep getting answers (NOT_SERVING) while the main listeners drain.
+        .add_admin_service(health_service)
+        .register_admin_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET);
+    if let Some(admin_listener) = admin_listener {
+        server = server.admin_listener(admin_listener);
+    }
+    if let Some(metrics_listener) = metrics_listener {
+        server = server.metrics_listener(metrics_listener);
+    }
+    if let Some(probes_listener) = probes_listener {
+        server = server.probes_listener(probes_listener);
+    }
+    let server = if listeners.is_empty() {
+        server.serve(&addresses).await?
+    } else {
+        server.serve_listeners(listeners).await?
     };
-    Ok(())
+    if let Some(handover) = handover {
+        // Only let the previous process drain once we are warm. If we never get there, it keeps serving when we exit.
+        if server.ready().await {
+            handover.ready()?;
+        }
+    }
+    Ok(server)
+}
+
+/// The `--health-check`s, with the settings that go with them.
+fn checkers(config: &Config) -> Vec<Checker> {
+    config
+        .health_checks
+        .iter()
+        .map(|check| {
+            Checker::boxed(check.clone().into_check())
+                .interval(Duration::from_millis(config.health_check_interval_ms))
+                .timeout(Duration::from_millis(config.health_check_timeout_ms))
+                .healthy_threshold(config.health_check_healthy_threshold)
+                .unhealthy_threshold(config.health_check_unhealthy_threshold)
+        })
+        .collect()
 }
 
 #[derive(Parser)]
+#[command(after_help = EXIT_CODES_HELP)]
 struct Args {
-    #[arg(long, default_value = "[::]:50051")]
-    address: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Read settings from this TOML file. Its keys are the names of the flags below, in snake_case (and `addresses`
+    /// for --address); environment variables and flags override them.
+    #[arg(long, global = true, env = "TONIC_SHUTDOWN_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Serve on an already bound TCP or Unix listening socket passed down as this file descriptor, instead of binding
+    /// --address. Can be repeated. Listeners from systemd socket activation (LISTEN_FDS) are picked up automatically.
+    #[arg(long = "listen-fd")]
+    listen_fds: Vec<RawFd>,
+
+    #[command(flatten)]
+    settings: Settings,
+}
 
-    #[arg(long)]
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Inspect the configuration.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum ConfigCommand {
+    /// Resolve the config file, environment variables and flags, check the result, and print it as TOML.
+    Check,
+}
+
+// The flags that can also be set in the config file. Unset ones fall back to the file, then to the defaults. (Not a doc
+// comment, which clap would turn into the `--help` description.)
+#[derive(clap::Args, Clone)]
+struct Settings {
+    /// Where to listen: `host:port` or `unix:/path/to.sock`. Can be repeated to serve the same services on several
+    /// addresses at once. Defaults to `[::]:50051`.
+    #[arg(
+        global = true,
+        long = "address",
+        env = "TONIC_SHUTDOWN_ADDRESSES",
+        value_delimiter = ','
+    )]
+    addresses: Vec<ListenAddr>,
+
+    /// Serve the admin service (tonic_shutdown.admin.v1.Admin), health, reflection and /metrics on a listener of their
+    /// own, which stays open while the main listeners drain and closes once the server has stopped. The admin service
+    /// is served on --address if unset.
+    #[arg(global = true, long, env = "TONIC_SHUTDOWN_ADMIN_ADDRESS")]
+    admin_address: Option<SocketAddr>,
+
+    /// Serve Prometheus metrics over HTTP at /metrics on this address. It stays open through the drain and after the
+    /// server stops, until the final values have been scraped.
+    #[arg(global = true, long, env = "TONIC_SHUTDOWN_METRICS_ADDRESS")]
+    metrics_address: Option<SocketAddr>,
+
+    /// After the server stops, wait this long for a final scrape of --metrics-address before exiting. Defaults to
+    /// 5000.
+    #[arg(global = true, long, env = "TONIC_SHUTDOWN_METRICS_FINAL_SCRAPE_MS")]
+    metrics_final_scrape_ms: Option<u64>,
+
+    /// Serve HTTP endpoints for Kubernetes on this address: /livez, /readyz (503 once a shutdown begins) and /prestop
+    /// (GET or POST; starts the shutdown and answers once the main listeners have closed).
+    #[arg(global = true, long, env = "TONIC_SHUTDOWN_PROBES_ADDRESS")]
+    probes_address: Option<SocketAddr>,
+
+    /// Serve TLS with this PEM certificate chain. It is reloaded on SIGHUP and whenever the file changes, without
+    /// affecting open connections. The admin service's own listener (--admin-address) stays plaintext.
+    #[arg(global = true, long, env = "TONIC_SHUTDOWN_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+
+    /// The PEM private key that goes with --tls-cert.
+    #[arg(global = true, long, env = "TONIC_SHUTDOWN_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+
+    /// Require clients to present a certificate signed by a CA in this PEM file (mutual TLS).
+    #[arg(global = true, long, env = "TONIC_SHUTDOWN_CLIENT_CA")]
+    client_ca: Option<PathBuf>,
+
+    /// After a shutdown signal, keep accepting connections for this long while reporting NOT_SERVING, so load
+    /// balancers can react before the listener closes. Defaults to 0.
+    #[arg(global = true, long, env = "TONIC_SHUTDOWN_LAME_DUCK_MS")]
+    lame_duck_ms: Option<u64>,
+
+    /// How long to wait for live streams before forcefully shutting down, counted from the shutdown signal (so it
+    /// includes the lame-duck period). Waits forever if unset.
+    #[arg(global = true, long, env = "TONIC_SHUTDOWN_GRACE_PERIOD_MS")]
     grace_period_ms: Option<u64>,
+
+    /// Close new connections to --address right away while this many are open. Lowering it on reload closes none of
+    /// the open ones. No limit if unset.
+    #[arg(global = true, long, env = "TONIC_SHUTDOWN_MAX_CONNECTIONS")]
+    max_connections: Option<usize>,
+
+    /// Once shutdown begins, end grpc.health.v1.Health/Watch streams this long after telling them NOT_SERVING, so
+    /// that health watchers don't block the drain. Watch streams are never ended if unset.
+    #[arg(
+        global = true,
+        long,
+        env = "TONIC_SHUTDOWN_HEALTH_WATCH_CLOSE_DELAY_MS"
+    )]
+    health_watch_close_delay_ms: Option<u64>,
+
+    /// The status that ended Watch streams get: `ok` (the default) or `unavailable`.
+    #[arg(global = true, long, env = "TONIC_SHUTDOWN_HEALTH_WATCH_CLOSE_WITH")]
+    health_watch_close_with: Option<WatchClose>,
+
+    /// The order in which services go NOT_SERVING once shutdown begins, by full name (e.g. grpc.health.v1.Health, or
+    /// '' for the server as a whole). Can be repeated. Unlisted services follow in the order they are served, then
+    /// the server as a whole.
+    #[arg(
+        global = true,
+        long,
+        env = "TONIC_SHUTDOWN_HEALTH_DRAIN_ORDER",
+        value_delimiter = ','
+    )]
+    health_drain_order: Vec<String>,
+
+    /// How long to wait between one service going NOT_SERVING and the next, see --health-drain-order. Defaults to 0.
+    #[arg(global = true, long, env = "TONIC_SHUTDOWN_HEALTH_DRAIN_STEP_MS")]
+    health_drain_step_ms: Option<u64>,
+
+    /// Pin a service's health status, as the admin SetServingStatus RPC does: `service=serving` or
+    /// `service=not_serving` (`=not_serving` for the server as a whole). Can be repeated. A shutdown still takes every
+    /// service to NOT_SERVING.
+    #[arg(
+        global = true,
+        long = "health-override",
+        env = "TONIC_SHUTDOWN_HEALTH_OVERRIDES",
+        value_delimiter = ','
+    )]
+    health_overrides: Vec<HealthOverride>,
+
+    /// Report every service as NOT_SERVING while this dependency is down: `tcp:host:port` (connects), `file:/path`
+    /// (exists) or `cmd:command` (exits 0, run with `sh -c`). Can be repeated.
+    #[arg(
+        global = true,
+        long = "health-check",
+        env = "TONIC_SHUTDOWN_HEALTH_CHECKS"
+    )]
+    health_checks: Vec<CheckSpec>,
+
+    /// How often to run each --health-check. Defaults to 5000.
+    #[arg(global = true, long, env = "TONIC_SHUTDOWN_HEALTH_CHECK_INTERVAL_MS")]
+    health_check_interval_ms: Option<u64>,
+
+    /// How long a --health-check may take before it counts as failed. Defaults to 1000.
+    #[arg(global = true, long, env = "TONIC_SHUTDOWN_HEALTH_CHECK_TIMEOUT_MS")]
+    health_check_timeout_ms: Option<u64>,
+
+    /// How many times in a row a failing --health-check has to pass before services are SERVING again. Defaults to 1.
+    #[arg(
+        global = true,
+        long,
+        env = "TONIC_SHUTDOWN_HEALTH_CHECK_HEALTHY_THRESHOLD"
+    )]
+    health_check_healthy_threshold: Option<u32>,
+
+    /// How many times in a row a passing --health-check has to fail before services go NOT_SERVING. Defaults to 3.
+    #[arg(
+        global = true,
+        long,
+        env = "TONIC_SHUTDOWN_HEALTH_CHECK_UNHEALTHY_THRESHOLD"
+    )]
+    health_check_unhealthy_threshold: Option<u32>,
+
+    /// Before reporting SERVING, wait for this to pass, retrying every --health-check-interval-ms. Takes the same
+    /// checks as --health-check, e.g. `cmd:./warm-cache.sh`. Can be repeated; they run concurrently.
+    #[arg(global = true, long = "warmup", env = "TONIC_SHUTDOWN_WARMUPS")]
+    warmups: Vec<CheckSpec>,
+
+    /// Give up on starting, and exit with code 5, if the --warmup checks have not all passed within this long. Waits
+    /// forever if unset.
+    #[arg(global = true, long, env = "TONIC_SHUTDOWN_STARTUP_TIMEOUT_MS")]
+    startup_timeout_ms: Option<u64>,
+
+    /// The exit code to use when streams had to be cut off because the grace period ran out. Defaults to 4. Must not
+    /// be one of the other exit codes.
+    #[arg(global = true, long, env = "TONIC_SHUTDOWN_FORCED_EXIT_CODE")]
+    forced_exit_code: Option<u8>,
+
+    /// Which logs to print, in `RUST_LOG` syntax, e.g. `info,tonic_shutdown_example=debug`. Defaults to `RUST_LOG`,
+    /// or `info` if that is unset too.
+    #[arg(global = true, long, env = "TONIC_SHUTDOWN_LOG_FILTER")]
+    log_filter: Option<String>,
+
+    /// How to print logs: `text` (the default), `json` or `compact`.
+    #[arg(global = true, long, env = "TONIC_SHUTDOWN_LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+}
+
+impl Settings {
+    /// Override `config` with whatever was set through flags or environment variables.
+    fn apply(self, config: &mut Config) {
+        let Settings {
+            addresses,
+            admin_address,
+            metrics_address,
+            metrics_final_scrape_ms,
+            probes_address,
+            tls_cert,
+            tls_key,
+            client_ca,
+            lame_duck_ms,
+            grace_period_ms,
+            max_connections,
+            health_watch_close_delay_ms,
+            health_watch_close_with,
+            health_drain_order,
+            health_drain_step_ms,
+            health_overrides,
+            health_checks,
+            health_check_interval_ms,
+            health_check_timeout_ms,
+            health_check_healthy_threshold,
+            health_check_unhealthy_threshold,
+            warmups,
+            startup_timeout_ms,
+            forced_exit_code,
+            log_filter,
+            log_format,
+        } = self;
+        if !addresses.is_empty() {
+            config.addresses = addresses;
+        }
+        config.admin_address = admin_address.or(config.admin_address);
+        config.metrics_address = metrics_address.or(config.metrics_address);
+        config.metrics_final_scrape_ms =
+            metrics_final_scrape_ms.unwrap_or(config.metrics_final_scrape_ms);
+        config.probes_address = probes_address.or(config.probes_address);
+        config.tls_cert = tls_cert.or(config.tls_cert.take());
+        config.tls_key = tls_key.or(config.tls_key.take());
+        config.client_ca = client_ca.or(config.client_ca.take());
+        config.lame_duck_ms = lame_duck_ms.unwrap_or(config.lame_duck_ms);
+        config.grace_period_ms = grace_period_ms.or(config.grace_period_ms);
+        config.max_connections = max_connections.or(config.max_connections);
+        config.health_watch_close_delay_ms =
+            health_watch_close_delay_ms.or(config.health_watch_close_delay_ms);
+        config.health_watch_close_with =
+            health_watch_close_with.unwrap_or(config.health_watch_close_with);
+        if !health_drain_order.is_empty() {
+            config.health_drain_order = health_drain_order;
+        }
+        config.health_drain_step_ms = health_drain_step_ms.unwrap_or(config.health_drain_step_ms);
+        if !health_overrides.is_empty() {
+            config.health_overrides = health_overrides;
+        }
+        if !health_checks.is_empty() {
+            config.health_checks = health_checks;
+        }
+        config.health_check_interval_ms =
+            health_check_interval_ms.unwrap_or(config.health_check_interval_ms);
+        config.health_check_timeout_ms =
+            health_check_timeout_ms.unwrap_or(config.health_check_timeout_ms);
+        config.health_check_healthy_threshold =
+            health_check_healthy_threshold.unwrap_or(config.health_check_healthy_threshold);
+        config.health_check_unhealthy_threshold =
+            health_check_unhealthy_threshold.unwrap_or(config.health_check_unhealthy_threshold);
+        if !warmups.is_empty() {
+            config.warmups = warmups;
+        }
+        config.startup_timeout_ms = startup_timeout_ms.or(config.startup_timeout_ms);
+        config.forced_exit_code = forced_exit_code.unwrap_or(config.forced_exit_code);
+        config.log_filter = log_filter.or(config.log_filter.take());
+        config.log_format = log_format.unwrap_or(config.log_format);
+    }
+}
+
+/// The defaults, overridden by the config file, overridden by environment variables and flags.
+fn resolve_config(path: Option<&Path>, settings: Settings) -> anyhow::Result<Config> {
+    let mut config = match path {
+        Some(path) => Config::from_file(path)?,
+        None => Config::default(),
+    };
+    settings.apply(&mut config);
+    config.validate()?;
+    Ok(config)
+}
+
+/// Print logs in `format`, filtered by [`env_filter`]. The filter can be swapped later through the returned handle.
+fn init_logging(format: LogFormat, filter: Option<&str>) -> LogFilter {
+    let (filter, handle) = reload::Layer::new(env_filter(filter));
+    let fmt = tracing_subscriber::fmt::layer();
+    let fmt = match format {
+        LogFormat::Text => fmt.boxed(),
+        LogFormat::Json => fmt.json().flatten_event(true).boxed(),
+        LogFormat::Compact => fmt.compact().boxed(),
+    };
+    tracing_subscriber::registry().with(filter).with(fmt).init();
+    handle
+}
+
+type LogFilter = reload::Handle<EnvFilter, tracing_subscriber::Registry>;
+
+/// `filter`, or else `RUST_LOG`, or else `info`.
+fn env_filter(filter: Option<&str>) -> EnvFilter {
+    match filter {
+        Some(filter) => EnvFilter::new(filter),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    }
+}
+
+/// Applies changes to the configuration on SIGHUP.
+struct Reloader {
+    path: Option<PathBuf>,
+    settings: Settings,
+    log_filter: LogFilter,
+    /// What is in effect right now.
+    current: tokio::sync::Mutex<Config>,
+}
+
+impl Reloader {
+    /// Resolve the configuration again, and apply the settings that can change while serving: the lame-duck and grace
+    /// periods (for the next shutdown), the connection limit, the log filter, health overrides and health checks.
+    /// Changes to anything else are logged and ignored until the next restart. An invalid configuration is rejected as
+    /// a whole. Reloads run one at a time.
+    async fn reload(&self, handle: &ServerHandle) {
+        let new = match resolve_config(self.path.as_deref(), self.settings.clone()) {
+            Ok(new) => new,
+            Err(err) => {
+                error!("rejected config reload, keeping the current settings: {err:#}");
+                return;
+            }
+        };
+        let mut current = self.current.lock().await;
+        if new.lame_duck_ms != current.lame_duck_ms {
+            info!(
+                "lame_duck_ms: {} -> {}",
+                current.lame_duck_ms, new.lame_duck_ms
+            );
+            handle.set_lame_duck(Duration::from_millis(new.lame_duck_ms));
+            current.lame_duck_ms = new.lame_duck_ms;
+        }
+        if new.grace_period_ms != current.grace_period_ms {
+            info!(
+                "grace_period_ms: {} -> {}",
+                unset_or(current.grace_period_ms),
+                unset_or(new.grace_period_ms)
+            );
+            handle.set_grace_period(new.grace_period_ms.map(Duration::from_millis));
+            current.grace_period_ms = new.grace_period_ms;
+        }
+        if new.max_connections != current.max_connections {
+            info!(
+                "max_connections: {} -> {}",
+                unset_or(current.max_connections),
+                unset_or(new.max_connections)
+            );
+            handle.set_max_connections(new.max_connections);
+            current.max_connections = new.max_connections;
+        }
+        if new.log_filter != current.log_filter {
+            match self
+                .log_filter
+                .reload(env_filter(new.log_filter.as_deref()))
+            {
+                Ok(()) => {
+                    info!(
+                        "log_filter: {} -> {}",
+                        unset_or(current.log_filter.as_ref()),
+                        unset_or(new.log_filter.as_ref())
+                    );
+                    current.log_filter = new.log_filter.clone();
+                }
+                Err(err) => error!("rejected log_filter change: {err}"),
+            }
+        }
+        if new.health_overrides != current.health_overrides {
+            current.health_overrides =
+                reload_health_overrides(handle, &current.health_overrides, &new.health_overrides)
+                    .await;
+        }
+        let checks_changed = new.health_checks != current.health_checks
+            || new.health_check_interval_ms != current.health_check_interval_ms
+            || new.health_check_timeout_ms != current.health_check_timeout_ms
+            || new.health_check_healthy_threshold != current.health_check_healthy_threshold
+            || new.health_check_unhealthy_threshold != current.health_check_unhealthy_threshold;
+        if checks_changed {
+            match handle.set_health_checks(checkers(&new)).await {
+                Ok(()) => {
+                    info!(
+                        "health_checks: [{}] -> [{}]",
+                        join(&current.health_checks),
+                        join(&new.health_checks)
+                    );
+                    current.health_checks = new.health_checks.clone();
+                    current.health_check_interval_ms = new.health_check_interval_ms;
+                    current.health_check_timeout_ms = new.health_check_timeout_ms;
+                    current.health_check_healthy_threshold = new.health_check_healthy_threshold;
+                    current.health_check_unhealthy_threshold = new.health_check_unhealthy_threshold;
+                }
+                Err(err) => error!("rejected health_checks change: {err:#}"),
+            }
+        }
+        let restart_only = [
+            ("addresses", new.addresses != current.addresses),
+            ("admin_address", new.admin_address != current.admin_address),
+            (
+                "metrics_address",
+                new.metrics_address != current.metrics_address,
+            ),
+            (
+                "metrics_final_scrape_ms",
+                new.metrics_final_scrape_ms != current.metrics_final_scrape_ms,
+            ),
+            (
+                "probes_address",
+                new.probes_address != current.probes_address,
+            ),
+            ("tls_cert", new.tls_cert != current.tls_cert),
+            ("tls_key", new.tls_key != current.tls_key),
+            ("client_ca", new.client_ca != current.client_ca),
+            (
+                "health_watch_close_delay_ms",
+                new.health_watch_close_delay_ms != current.health_watch_close_delay_ms,
+            ),
+            (
+                "health_watch_close_with",
+                new.health_watch_close_with != current.health_watch_close_with,
+            ),
+            (
+                "health_drain_order",
+                new.health_drain_order != current.health_drain_order,
+            ),
+            (
+                "health_drain_step_ms",
+                new.health_drain_step_ms != current.health_drain_step_ms,
+            ),
+            ("warmups", new.warmups != current.warmups),
+            (
+                "startup_timeout_ms",
+                new.startup_timeout_ms != current.startup_timeout_ms,
+            ),
+            (
+                "forced_exit_code",
+                new.forced_exit_code != current.forced_exit_code,
+            ),
+            ("log_format", new.log_format != current.log_format),
+        ];
+        for (key, _) in restart_only.iter().filter(|(_, changed)| *changed) {
+            warn!("rejected {key} change: it only takes effect after a restart");
+        }
+    }
+}
+
+/// Pin and unpin services so that the overrides from the config go from `current` to `new`, and return the ones now
+/// in effect. Services that are not in either list keep whatever was pinned through the admin service.
+async fn reload_health_overrides(
+    handle: &ServerHandle,
+    current: &[HealthOverride],
+    new: &[HealthOverride],
+) -> Vec<HealthOverride> {
+    let Some(health) = handle.health() else {
+        error!("rejected health_overrides change: the server has no health reporter");
+        return current.to_vec();
+    };
+    let mut applied = Vec::new();
+    for old in current {
+        if new.iter().any(|new| new.service == old.service) {
+            continue;
+        }
+        match health.clear_override(&old.service).await {
+            Ok(()) => info!("health_overrides: unpinned {:?}", old.service),
+            Err(err) => {
+                error!(
+                    "rejected health_overrides change to {:?}: {err:#}",
+                    old.service
+                );
+                applied.push(old.clone());
+            }
+        }
+    }
+    for new in new {
+        if current.contains(new) {
+            applied.push(new.clone());
+            continue;
+        }
+        match health.set_override(&new.service, new.status).await {
+            Ok(()) => {
+                info!("health_overrides: pinned {new}");
+                applied.push(new.clone());
+            }
+            Err(err) => {
+                error!("rejected health_overrides change to {new}: {err:#}");
+                // Whatever was pinned before stays pinned.
+                if let Some(old) = current.iter().find(|old| old.service == new.service) {
+                    applied.push(old.clone());
+                }
+            }
+        }
+    }
+    applied
+}
+
+fn join(items: &[impl std::fmt::Display]) -> String {
+    items
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn unset_or(value: Option<impl std::fmt::Display>) -> String {
+    value.map_or_else(|| "unset".to_owned(), |value| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reload_applies_only_valid_reloadable_changes() {
+        let path = env::temp_dir().join(format!("reload-{}.toml", std::process::id()));
+        let write = |max_connections, lame_duck_ms, address| {
+            let config = format!(
+                "addresses = [\"{address}\"]\nmax_connections = {max_connections}\nlame_duck_ms = {lame_duck_ms}\n"
+            );
+            fs::write(&path, config).unwrap();
+        };
+        write(1, 100, "127.0.0.1:0");
+        let settings = Args::parse_from(["test"]).settings;
+        let config = resolve_config(Some(&path), settings.clone()).unwrap();
+        let handle = GracefulServer::builder()
+            .max_connections(config.max_connections)
+            .lame_duck(Duration::from_millis(config.lame_duck_ms))
+            .serve(&config.addresses)
+            .await
+            .unwrap();
+        let (_filter, log_filter) = reload::Layer::new(env_filter(None));
+        let reloader = Reloader {
+            path: Some(path.clone()),
+            settings,
+            log_filter,
+            current: tokio::sync::Mutex::new(config.clone()),
+        };
+
+        // The addresses only change on a restart.
+        write(2, 200, "127.0.0.1:1");
+        reloader.reload(&handle).await;
+        assert_eq!(handle.max_connections(), Some(2));
+        assert_eq!(handle.lame_duck(), Duration::from_millis(200));
+        let current = reloader.current.lock().await.clone();
+        assert_eq!(current.max_connections, Some(2));
+        assert_eq!(current.lame_duck_ms, 200);
+        assert_eq!(current.addresses, config.addresses);
+
+        // An invalid config is rejected as a whole.
+        write(0, 300, "127.0.0.1:0");
+        reloader.reload(&handle).await;
+        assert_eq!(handle.max_connections(), Some(2));
+        assert_eq!(handle.lame_duck(), Duration::from_millis(200));
+        assert_eq!(reloader.current.lock().await.lame_duck_ms, 200);
+
+        fs::remove_file(&path).unwrap();
+    }
 }
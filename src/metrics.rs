@@ -0,0 +1,628 @@
+//! Prometheus metrics for connections, RPCs and the shutdown itself, in the text exposition format.
+//!
+//! Gauges (open connections and streams, the current phase) are read from the [`Registry`] and the [`Lifecycle`] at
+//! scrape time. Counters and histograms are recorded by the [`MetricsLayer`] as responses finish, and by lifecycle
+//! hooks as the drain progresses.
+//!
+//! The `method` label is the full gRPC path of a method the server serves. Clients can send any path they like, so to
+//! keep the number of series bounded, paths outside the served services are counted as `unknown`, and so are RPCs
+//! that fail with `UNIMPLEMENTED` on a method whose descriptor was never registered with the [`Registry`] (which is
+//! how generated services answer methods they do not have).
+
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt::Write as _,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use http_body::{Body, Frame, SizeHint};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tonic::{body::BoxBody, Code, Status};
+use tower::{Layer, Service};
+
+use crate::{
+    http_server,
+    lifecycle::{Lifecycle, Phase},
+    listener::Listener,
+    registry::{Registry, StreamKind},
+};
+
+/// Upper bounds of the RPC latency buckets, in seconds.
+const RPC_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+/// Upper bounds of the drain duration buckets, in seconds.
+const DRAIN_BUCKETS: &[f64] = &[
+    0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0,
+];
+
+/// Collects the server's metrics, and renders them for Prometheus.
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<Mutex<Inner>>,
+    registry: Registry,
+    lifecycle: Lifecycle,
+    /// The full names of the services the server serves, for the `method` label.
+    services: Arc<[&'static str]>,
+    /// Whether anyone has scraped us yet.
+    scraped: Arc<AtomicBool>,
+    /// Cancelled by the first scrape after the server has stopped.
+    final_scrape: CancellationToken,
+}
+
+struct Inner {
+    /// Finished RPCs by method and status code (as an `i32`, since `Code` is not `Ord`).
+    handled: BTreeMap<(String, i32), u64>,
+    latency: BTreeMap<String, Histogram>,
+    drain: Histogram,
+    /// When the current shutdown began, if one is under way.
+    drain_started: Option<Instant>,
+    force_closed_streams: u64,
+}
+
+impl Metrics {
+    /// Start recording the metrics of the server that `registry` and `lifecycle` belong to, which serves `services`
+    /// (by their full names, e.g. `helloworld.Greeter`).
+    pub fn new(registry: Registry, lifecycle: Lifecycle, services: &[&'static str]) -> Self {
+        let metrics = Self {
+            inner: Arc::new(Mutex::new(Inner {
+                handled: BTreeMap::new(),
+                latency: BTreeMap::new(),
+                drain: Histogram::new(DRAIN_BUCKETS),
+                drain_started: None,
+                force_closed_streams: 0,
+            })),
+            registry,
+            lifecycle,
+            services: services.into(),
+            scraped: Arc::new(AtomicBool::new(false)),
+            final_scrape: CancellationToken::new(),
+        };
+        metrics.lifecycle.on_transition({
+            let metrics = metrics.clone();
+            move |prev, next| metrics.on_transition(prev, next)
+        });
+        metrics
+    }
+
+    fn on_transition(&self, prev: &Phase, next: &Phase) {
+        let mut inner = self.inner.lock().unwrap();
+        match (prev, next) {
+            // Only a server that was serving drains: one that failed to start, or died, stops without a drain.
+            (Phase::Serving, Phase::LameDuck { .. } | Phase::Draining { .. }) => {
+                inner.drain_started = Some(Instant::now());
+            }
+            // An aborted lame duck is not a drain.
+            (Phase::LameDuck { .. }, Phase::Serving) => inner.drain_started = None,
+            (_, Phase::ForceClosing { .. }) => {
+                inner.force_closed_streams += self.registry.streams().len() as u64;
+            }
+            (_, Phase::Stopped { .. }) => {
+                if let Some(started) = inner.drain_started.take() {
+                    inner.drain.observe(started.elapsed());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The `method` label for an RPC to `path`, given the stream kind registered for it and, once it has one, its
+    /// status code.
+    fn method_label<'a>(&self, path: &'a str, kind: StreamKind, code: Option<Code>) -> &'a str {
+        if kind != StreamKind::Unknown {
+            return path;
+        }
+        let served = path
+            .strip_prefix('/')
+            .and_then(|path| path.split_once('/'))
+            .is_some_and(|(service, method)| {
+                !method.is_empty() && !method.contains('/') && self.services.contains(&service)
+            });
+        if served && code != Some(Code::Unimplemented) {
+            path
+        } else {
+            "unknown"
+        }
+    }
+
+    fn finished(&self, path: &str, code: Code, elapsed: Duration) {
+        let method = self.method_label(path, self.registry.kind(path), Some(code));
+        let mut inner = self.inner.lock().unwrap();
+        *inner
+            .handled
+            .entry((method.to_owned(), code as i32))
+            .or_default() += 1;
+        inner
+            .latency
+            .entry(method.to_owned())
+            .or_insert_with(|| Histogram::new(RPC_BUCKETS))
+            .observe(elapsed);
+    }
+
+    /// Answer a scrape. Once the server has stopped, this is the final scrape that [`Metrics::final_scrape`] waits for.
+    pub(crate) fn scrape(&self) -> http_server::Response {
+        self.scraped.store(true, Ordering::Relaxed);
+        let stopped = matches!(self.lifecycle.phase(), Phase::Stopped { .. });
+        let mut response = http_server::text(http::StatusCode::OK, self.render());
+        response.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+        );
+        if stopped {
+            self.final_scrape.cancel();
+        }
+        response
+    }
+
+    /// Whether anyone has scraped the metrics so far, i.e. whether there is a scraper to wait for.
+    pub(crate) fn scraped(&self) -> bool {
+        self.scraped.load(Ordering::Relaxed)
+    }
+
+    /// Wait for the server to stop, and then for one more scrape to pick up the final values, or for `timeout` to run
+    /// out.
+    pub(crate) async fn final_scrape(&self, timeout: Duration) {
+        let mut phase = self.lifecycle.subscribe();
+        let _ = phase
+            .wait_for(|phase| matches!(phase, Phase::Stopped { .. }))
+            .await;
+        let _ = tokio::time::timeout(timeout, self.final_scrape.cancelled()).await;
+    }
+
+    /// Every metric, in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let phase = self.lifecycle.phase();
+        let mut streams = BTreeMap::<String, u64>::new();
+        for stream in self.registry.streams() {
+            let method = self.method_label(&stream.method, stream.kind, None);
+            *streams.entry(method.to_owned()).or_default() += 1;
+        }
+        let connections = self.registry.connections().len();
+        let inner = self.inner.lock().unwrap();
+        // Methods we have seen before stay at zero rather than disappearing.
+        for method in inner.latency.keys() {
+            streams.entry(method.clone()).or_default();
+        }
+
+        let mut out = String::new();
+        header(
+            &mut out,
+            "tonic_shutdown_connections",
+            "gauge",
+            "Open connections.",
+        );
+        let _ = writeln!(out, "tonic_shutdown_connections {connections}");
+        header(
+            &mut out,
+            "tonic_shutdown_streams",
+            "gauge",
+            "In-flight RPCs.",
+        );
+        for (method, count) in &streams {
+            let method = escape(method);
+            let _ = writeln!(out, "tonic_shutdown_streams{{method=\"{method}\"}} {count}");
+        }
+        header(
+            &mut out,
+            "grpc_server_handled_total",
+            "counter",
+            "Finished RPCs.",
+        );
+        for ((method, code), count) in &inner.handled {
+            let _ = writeln!(
+                out,
+                "grpc_server_handled_total{{method=\"{}\",code=\"{:?}\"}} {count}",
+                escape(method),
+                Code::from(*code)
+            );
+        }
+        header(
+            &mut out,
+            "grpc_server_handling_seconds",
+            "histogram",
+            "How long RPCs took, from the request to the end of the response.",
+        );
+        for (method, histogram) in &inner.latency {
+            histogram.render(
+                &mut out,
+                "grpc_server_handling_seconds",
+                &format!("method=\"{}\",", escape(method)),
+            );
+        }
+        header(
+            &mut out,
+            "tonic_shutdown_phase",
+            "gauge",
+            "The current lifecycle phase: 1 for the current one, 0 for the others.",
+        );
+        for name in Phase::NAMES {
+            let current = u8::from(name == phase.name());
+            let _ = writeln!(out, "tonic_shutdown_phase{{phase=\"{name}\"}} {current}");
+        }
+        header(
+            &mut out,
+            "tonic_shutdown_drain_seconds",
+            "histogram",
+            "How long shutdowns took, from the request to the server stopping.",
+        );
+        inner
+            .drain
+            .render(&mut out, "tonic_shutdown_drain_seconds", "");
+        header(
+            &mut out,
+            "tonic_shutdown_force_closed_streams_total",
+            "counter",
+            "Streams that were still open when the grace period ran out.",
+        );
+        let _ = writeln!(
+            out,
+            "tonic_shutdown_force_closed_streams_total {}",
+            inner.force_closed_streams
+        );
+        out
+    }
+}
+
+/// Escape a label value for the text exposition format.
+fn escape(value: &str) -> Cow<'_, str> {
+    if !value.contains(['\\', '"', '\n']) {
+        return Cow::Borrowed(value);
+    }
+    let mut escaped = String::with_capacity(value.len() + 2);
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations per bucket, not cumulative.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: Duration) {
+        let value = value.as_secs_f64();
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// `labels` is either empty or ends with a comma.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{{labels}le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{labels}le=\"+Inf\"}} {}", self.count);
+        let labels = match labels.trim_end_matches(',') {
+            "" => String::new(),
+            labels => format!("{{{labels}}}"),
+        };
+        let _ = writeln!(out, "{name}_sum{labels} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{labels} {}", self.count);
+    }
+}
+
+/// Serve `/metrics` on `listener` until the server stops, and then until one more scrape has picked up the final
+/// values, or `final_scrape_timeout` runs out.
+pub(crate) async fn serve(listener: Listener, metrics: Metrics, final_scrape_timeout: Duration) {
+    let handler = {
+        let metrics = metrics.clone();
+        move |req: http_server::Request| {
+            let metrics = metrics.clone();
+            async move {
+                match req.uri().path() {
+                    "/metrics" => metrics.scrape(),
+                    _ => http_server::text(http::StatusCode::NOT_FOUND, "not found\n"),
+                }
+            }
+        }
+    };
+    http_server::serve(listener, handler, async move {
+        metrics.final_scrape(final_scrape_timeout).await;
+    })
+    .await;
+}
+
+/// Answers `GET /metrics` in front of a gRPC server (one that accepts HTTP/1), so that its listener can be scraped
+/// too.
+#[derive(Clone)]
+pub(crate) struct ScrapeLayer {
+    metrics: Metrics,
+}
+
+impl ScrapeLayer {
+    pub(crate) fn new(metrics: Metrics) -> Self {
+        Self { metrics }
+    }
+}
+
+impl<S> Layer<S> for ScrapeLayer {
+    type Service = ScrapeService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ScrapeService {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ScrapeService<S> {
+    inner: S,
+    metrics: Metrics,
+}
+
+impl<S, ReqBody> Service<http::Request<ReqBody>> for ScrapeService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
+        if req.method() == http::Method::GET && req.uri().path() == "/metrics" {
+            let response = self.metrics.scrape().map(tonic::body::boxed);
+            return Box::pin(async move { Ok(response) });
+        }
+        Box::pin(self.inner.call(req))
+    }
+}
+
+/// Records the status code and latency of every RPC in [`Metrics`].
+#[derive(Clone)]
+pub struct MetricsLayer {
+    metrics: Metrics,
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: Metrics) -> Self {
+        Self { metrics }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    metrics: Metrics,
+}
+
+impl<S, ReqBody> Service<http::Request<ReqBody>> for MetricsService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
+        let mut observer = Observer {
+            metrics: self.metrics.clone(),
+            method: req.uri().path().to_owned(),
+            started_at: Instant::now(),
+            code: None,
+        };
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let resp = fut.await?;
+            // A trailers-only response carries its status in the headers.
+            observer.code = Status::from_header_map(resp.headers()).map(|status| status.code());
+            Ok(resp.map(|body| {
+                tonic::body::boxed(ObservedBody {
+                    inner: body,
+                    observer,
+                })
+            }))
+        })
+    }
+}
+
+/// Records an RPC when it is dropped: once its response body is done, or when the server gives up on it.
+struct Observer {
+    metrics: Metrics,
+    method: String,
+    started_at: Instant,
+    /// `None` until we have seen a `grpc-status`.
+    code: Option<Code>,
+}
+
+impl Drop for Observer {
+    fn drop(&mut self) {
+        // No status means the client went away before we could send one.
+        let code = self.code.unwrap_or(Code::Cancelled);
+        self.metrics
+            .finished(&self.method, code, self.started_at.elapsed());
+    }
+}
+
+struct ObservedBody {
+    inner: BoxBody,
+    observer: Observer,
+}
+
+impl Body for ObservedBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = std::task::ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(trailers) = frame.trailers_ref() {
+                    if let Some(status) = Status::from_header_map(trailers) {
+                        self.observer.code = Some(status.code());
+                    }
+                }
+            }
+            Some(Err(status)) => self.observer.code = Some(status.code()),
+            None => {}
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ShutdownReason;
+
+    const HEALTH: &str = "grpc.health.v1.Health";
+    const GREETER: &str = "helloworld.Greeter";
+
+    fn metrics() -> Metrics {
+        let registry = Registry::new();
+        registry
+            .register_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+            .unwrap();
+        Metrics::new(registry, Lifecycle::new(), &[HEALTH, GREETER])
+    }
+
+    #[test]
+    fn labels_only_served_methods() {
+        let metrics = metrics();
+        let label = |path, code| metrics.method_label(path, metrics.registry.kind(path), code);
+        // Registered descriptors are trusted whatever the outcome.
+        let check = "/grpc.health.v1.Health/Check";
+        assert_eq!(label(check, Some(Code::Unimplemented)), check);
+        // Otherwise a served service is enough, unless it did not know the method.
+        let hello = "/helloworld.Greeter/SayHello";
+        assert_eq!(label(hello, None), hello);
+        assert_eq!(label(hello, Some(Code::Ok)), hello);
+        assert_eq!(label(hello, Some(Code::Unimplemented)), "unknown");
+        for path in [
+            "/helloworld.Farewell/SayGoodbye",
+            "/helloworld.Greeter/",
+            "/helloworld.Greeter/Say/Hello",
+            "/helloworld.Greeter",
+            "helloworld.Greeter/SayHello",
+            "/",
+        ] {
+            assert_eq!(label(path, Some(Code::Ok)), "unknown", "{path}");
+        }
+    }
+
+    #[test]
+    fn counts_unknown_methods_together() {
+        let metrics = metrics();
+        for path in ["/a.B/C", "/d.E/F", "/helloworld.Greeter/Nope"] {
+            metrics.finished(path, Code::Unimplemented, Duration::ZERO);
+        }
+        metrics.finished("/helloworld.Greeter/SayHello", Code::Ok, Duration::ZERO);
+        let rendered = metrics.render();
+        assert!(rendered
+            .contains("grpc_server_handled_total{method=\"unknown\",code=\"Unimplemented\"} 3\n"));
+        assert!(rendered.contains(
+            "grpc_server_handled_total{method=\"/helloworld.Greeter/SayHello\",code=\"Ok\"} 1\n"
+        ));
+        assert!(!rendered.contains("/a.B/C"));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert!(matches!(escape("/a.B/C"), Cow::Borrowed("/a.B/C")));
+        assert_eq!(escape("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+
+    #[test]
+    fn times_only_drains_of_a_serving_server() {
+        let admin = || ShutdownReason::Admin("test".to_owned());
+        let drains = |metrics: &Metrics| {
+            let inner = metrics.inner.lock().unwrap();
+            assert!(inner.drain_started.is_none());
+            inner.drain.count
+        };
+
+        let failed_start = metrics();
+        let lifecycle = &failed_start.lifecycle;
+        lifecycle.begin_shutdown(admin(), Duration::ZERO, None);
+        lifecycle.stopped();
+        assert_eq!(drains(&failed_start), 0);
+
+        let died = metrics();
+        died.lifecycle.serving();
+        died.lifecycle
+            .failed(ShutdownReason::Fatal("test".to_owned()));
+        assert_eq!(drains(&died), 0);
+
+        let drained = metrics();
+        let lifecycle = &drained.lifecycle;
+        lifecycle.serving();
+        lifecycle.begin_shutdown(admin(), Duration::from_secs(1), None);
+        lifecycle.abort_shutdown();
+        assert_eq!(drains(&drained), 0);
+        lifecycle.begin_shutdown(admin(), Duration::from_secs(1), None);
+        lifecycle.drain();
+        lifecycle.stopped();
+        assert_eq!(drains(&drained), 1);
+    }
+}
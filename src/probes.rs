@@ -0,0 +1,158 @@
+//! Plain HTTP endpoints for Kubernetes probes and `preStop` hooks, which cannot speak gRPC health.
+//!
+//! - `/livez` answers 200 for as long as the process is up. A draining server is still alive, and restarting it
+//!   would only cut the drain short.
+//! - `/readyz` answers 200 while serving, and 503 from the moment a shutdown begins (lame-duck period included),
+//!   just like the health service reports `NOT_SERVING`.
+//! - `/prestop` starts a shutdown, lame-duck period and all, and only answers once the main listeners have closed.
+//!   The kubelet waits for the hook before sending SIGTERM, which then finds the drain already under way. Since
+//!   the kubelet's `httpGet` hooks send a GET, `/prestop` takes GET as well as POST, and answers anything else with
+//!   405 so that a stray HEAD or OPTIONS request can't shut the server down.
+
+use http::{Method, StatusCode};
+
+use crate::{
+    http_server::{self, Request, Response},
+    lifecycle::{Phase, ShutdownReason},
+    listener::Listener,
+    ServerHandle,
+};
+
+/// Serve the probe endpoints on `listener` until the server has stopped.
+pub(crate) async fn serve(listener: Listener, handle: ServerHandle) {
+    let mut phase = handle.lifecycle().subscribe();
+    let handler = {
+        let handle = handle.clone();
+        move |req: Request| {
+            let handle = handle.clone();
+            async move { respond(&handle, req).await }
+        }
+    };
+    http_server::serve(listener, handler, async move {
+        let _ = phase
+            .wait_for(|phase| matches!(phase, Phase::Stopped { .. }))
+            .await;
+    })
+    .await;
+}
+
+async fn respond(handle: &ServerHandle, req: Request) -> Response {
+    match req.uri().path() {
+        "/livez" => http_server::text(StatusCode::OK, "ok\n"),
+        "/readyz" => match handle.state() {
+            Phase::Serving => http_server::text(StatusCode::OK, "ok\n"),
+            phase => http_server::text(StatusCode::SERVICE_UNAVAILABLE, format!("{phase}\n")),
+        },
+        "/prestop" if matches!(*req.method(), Method::GET | Method::POST) => prestop(handle).await,
+        "/prestop" => {
+            let mut response =
+                http_server::text(StatusCode::METHOD_NOT_ALLOWED, "use GET or POST\n");
+            response.headers_mut().insert(
+                http::header::ALLOW,
+                http::HeaderValue::from_static("GET, POST"),
+            );
+            response
+        }
+        _ => http_server::text(StatusCode::NOT_FOUND, "not found\n"),
+    }
+}
+
+/// Start the shutdown if nothing else has, and wait for the main listeners to close.
+async fn prestop(handle: &ServerHandle) -> Response {
+    handle.shutdown(ShutdownReason::Admin("preStop hook".to_owned()));
+    let mut phase = handle.lifecycle().subscribe();
+    // The lifecycle lives as long as `handle`, so the channel can't close out from under us.
+    let closed = phase
+        .wait_for(|phase| phase.is_draining() || !phase.is_shutting_down())
+        .await
+        .is_ok_and(|phase| phase.is_draining());
+    if closed {
+        http_server::text(StatusCode::OK, "listeners closed\n")
+    } else {
+        // Someone aborted the shutdown during the lame-duck period.
+        http_server::text(StatusCode::CONFLICT, "shutdown aborted\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    use super::*;
+    use crate::{listener::ListenAddr, GracefulServer};
+
+    async fn server() -> ServerHandle {
+        let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+        GracefulServer::builder()
+            .probes(Some(localhost))
+            .lame_duck(Duration::from_millis(300))
+            .serve(&[ListenAddr::Tcp(localhost)])
+            .await
+            .unwrap()
+    }
+
+    /// The status and the raw response.
+    async fn request(handle: &ServerHandle, method: &str, path: &str) -> (u16, String) {
+        let Some(ListenAddr::Tcp(address)) = handle.probes_addr() else {
+            panic!("no probes address");
+        };
+        let mut conn = tokio::net::TcpStream::connect(address).await.unwrap();
+        let request =
+            format!("{method} {path} HTTP/1.1\r\nhost: probes\r\nconnection: close\r\n\r\n");
+        conn.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        (status, response)
+    }
+
+    #[tokio::test]
+    async fn prestop_starts_the_shutdown_and_waits_for_the_drain() {
+        let handle = server().await;
+        assert_eq!(request(&handle, "GET", "/livez").await.0, 200);
+        assert_eq!(request(&handle, "GET", "/readyz").await.0, 200);
+        assert_eq!(request(&handle, "GET", "/nope").await.0, 404);
+        let (status, response) = request(&handle, "HEAD", "/prestop").await;
+        assert_eq!(status, 405);
+        assert!(response.contains("allow: GET, POST\r\n"), "{response}");
+        assert_eq!(handle.state(), Phase::Serving);
+
+        let prestop = tokio::spawn({
+            let handle = handle.clone();
+            async move { request(&handle, "GET", "/prestop").await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // Still in the lame-duck period: alive, but not ready.
+        assert!(!prestop.is_finished());
+        assert_eq!(request(&handle, "GET", "/livez").await.0, 200);
+        let (status, response) = request(&handle, "GET", "/readyz").await;
+        assert_eq!(status, 503);
+        assert!(
+            response.ends_with("lame duck (admin request: preStop hook)\n"),
+            "{response}"
+        );
+
+        let (status, response) = prestop.await.unwrap();
+        assert_eq!(status, 200);
+        assert!(response.ends_with("listeners closed\n"), "{response}");
+        handle.wait().await;
+    }
+
+    #[tokio::test]
+    async fn prestop_reports_an_aborted_shutdown() {
+        let handle = server().await;
+        let prestop = tokio::spawn({
+            let handle = handle.clone();
+            async move { request(&handle, "POST", "/prestop").await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(handle.lifecycle().abort_shutdown());
+
+        let (status, response) = prestop.await.unwrap();
+        assert_eq!(status, 409);
+        assert!(response.ends_with("shutdown aborted\n"), "{response}");
+        assert_eq!(request(&handle, "GET", "/readyz").await.0, 200);
+    }
+}
@@ -0,0 +1,555 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use http_body::{Body, Frame, SizeHint};
+use prost::Message;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Instant,
+};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+use tonic::{
+    body::BoxBody,
+    transport::server::{Connected, TcpConnectInfo},
+    Status,
+};
+use tower::{Layer, Service};
+
+/// What shape of RPC a stream belongs to, as declared in its service's protobuf definition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamKind {
+    Unary,
+    ClientStreaming,
+    ServerStreaming,
+    Bidirectional,
+    /// The method's descriptor was never registered with [`Registry::register_file_descriptor_set`].
+    Unknown,
+}
+
+impl fmt::Display for StreamKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StreamKind::Unary => "unary",
+            StreamKind::ClientStreaming => "client streaming",
+            StreamKind::ServerStreaming => "server streaming",
+            StreamKind::Bidirectional => "bidirectional",
+            StreamKind::Unknown => "unknown",
+        })
+    }
+}
+
+/// A live connection.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub peer: Option<SocketAddr>,
+    pub opened_at: Instant,
+}
+
+/// An in-flight RPC. It stays in the registry until its response (trailers included) has been sent, or the client
+/// goes away.
+#[derive(Clone, Debug)]
+pub struct StreamInfo {
+    /// The full gRPC path, e.g. `/grpc.health.v1.Health/Watch`.
+    pub method: String,
+    pub kind: StreamKind,
+    pub peer: Option<SocketAddr>,
+    pub started_at: Instant,
+}
+
+impl StreamInfo {
+    /// The method path without the protobuf package, e.g. `Health/Watch`.
+    pub fn short_method(&self) -> &str {
+        let method = self.method.trim_start_matches('/');
+        match method.split_once('/') {
+            Some((service, _)) => match service.rfind('.') {
+                Some(dot) => &method[dot + 1..],
+                None => method,
+            },
+            None => method,
+        }
+    }
+}
+
+impl fmt::Display for StreamInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.short_method())?;
+        if self.kind != StreamKind::Unknown {
+            write!(f, " ({})", self.kind)?;
+        }
+        match self.peer {
+            Some(peer) => write!(f, " from {}", peer.ip())?,
+            None => f.write_str(" from unknown peer")?,
+        }
+        write!(f, " open {}s", self.started_at.elapsed().as_secs())
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    connections: BTreeMap<u64, ConnectionInfo>,
+    streams: BTreeMap<u64, StreamInfo>,
+    kinds: HashMap<String, StreamKind>,
+}
+
+impl Inner {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn kind(&self, method: &str) -> StreamKind {
+        self.kinds
+            .get(method)
+            .copied()
+            .unwrap_or(StreamKind::Unknown)
+    }
+}
+
+/// Keeps track of every live connection and in-flight stream, so that we can tell what is blocking a shutdown.
+///
+/// Connections are recorded by wrapping accepted IO in [`TrackedIo`], streams by the [`RegistryLayer`]. The layer
+/// also lets us cut every stream off with [`Registry::cancel_all`].
+#[derive(Clone, Default)]
+pub struct Registry {
+    inner: Arc<Mutex<Inner>>,
+    cancel: CancellationToken,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Learn the stream kinds of every method in an encoded `FileDescriptorSet`, such as the `FILE_DESCRIPTOR_SET`
+    /// constants that generated tonic code exposes for reflection.
+    pub fn register_file_descriptor_set(&self, encoded: &[u8]) -> Result<(), prost::DecodeError> {
+        let set = prost_types::FileDescriptorSet::decode(encoded)?;
+        let mut inner = self.inner.lock().unwrap();
+        for file in &set.file {
+            let package = file.package();
+            for service in &file.service {
+                for method in &service.method {
+                    let path = if package.is_empty() {
+                        format!("/{}/{}", service.name(), method.name())
+                    } else {
+                        format!("/{package}.{}/{}", service.name(), method.name())
+                    };
+                    let kind = match (method.client_streaming(), method.server_streaming()) {
+                        (false, false) => StreamKind::Unary,
+                        (true, false) => StreamKind::ClientStreaming,
+                        (false, true) => StreamKind::ServerStreaming,
+                        (true, true) => StreamKind::Bidirectional,
+                    };
+                    inner.kinds.insert(path, kind);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The stream kind of `method` (a full gRPC path), as registered with [`Registry::register_file_descriptor_set`].
+    pub fn kind(&self, method: &str) -> StreamKind {
+        self.inner.lock().unwrap().kind(method)
+    }
+
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.inner
+            .lock()
+            .unwrap()
+            .connections
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Every in-flight stream, oldest first.
+    pub fn streams(&self) -> Vec<StreamInfo> {
+        self.inner
+            .lock()
+            .unwrap()
+            .streams
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// A one-line summary of what is still open, listing at most `limit` streams (or none, if `limit` is zero).
+    pub fn summary(&self, limit: usize) -> String {
+        let (connections, streams) = {
+            let inner = self.inner.lock().unwrap();
+            (inner.connections.len(), inner.streams.len())
+        };
+        let mut summary = format!(
+            "{streams} {} remaining on {connections} {}",
+            if streams == 1 { "stream" } else { "streams" },
+            if connections == 1 {
+                "connection"
+            } else {
+                "connections"
+            },
+        );
+        for (i, stream) in self.streams().iter().take(limit).enumerate() {
+            summary.push_str(if i == 0 { ": " } else { ", " });
+            summary.push_str(&stream.to_string());
+        }
+        if limit > 0 && streams > limit {
+            summary.push_str(&format!(", and {} more", streams - limit));
+        }
+        summary
+    }
+
+    /// End every in-flight stream, and every stream opened from now on, with an `UNAVAILABLE` status. Handlers that
+    /// have not responded yet are dropped; streams that are mid-response get their trailers right away. Returns how
+    /// many streams were open.
+    pub fn cancel_all(&self) -> usize {
+        self.cancel.cancel();
+        self.inner.lock().unwrap().streams.len()
+    }
+
+    /// Wrap an accepted connection so that it is tracked until it is dropped.
+    pub fn track<IO>(&self, io: IO, peer: Option<SocketAddr>) -> TrackedIo<IO> {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id();
+        inner.connections.insert(
+            id,
+            ConnectionInfo {
+                peer,
+                opened_at: Instant::now(),
+            },
+        );
+        TrackedIo {
+            inner: io,
+            _guard: Guard {
+                registry: self.clone(),
+                id,
+            },
+        }
+    }
+
+    fn open_stream(&self, method: &str, peer: Option<SocketAddr>) -> Guard {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id();
+        let kind = inner.kind(method);
+        inner.streams.insert(
+            id,
+            StreamInfo {
+                method: method.to_owned(),
+                kind,
+                peer,
+                started_at: Instant::now(),
+            },
+        );
+        Guard {
+            registry: self.clone(),
+            id,
+        }
+    }
+}
+
+/// Removes a connection or stream from the registry when dropped. Connections and streams share an id space, so we
+/// can simply remove the id from both.
+struct Guard {
+    registry: Registry,
+    id: u64,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let mut inner = self.registry.inner.lock().unwrap();
+        inner.connections.remove(&self.id);
+        inner.streams.remove(&self.id);
+    }
+}
+
+/// An accepted connection that stays in its [`Registry`] until it is closed.
+pub struct TrackedIo<IO> {
+    inner: IO,
+    _guard: Guard,
+}
+
+impl<IO: Connected> Connected for TrackedIo<IO> {
+    type ConnectInfo = IO::ConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.inner.connect_info()
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for TrackedIo<IO> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for TrackedIo<IO> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+/// Records every request in a [`Registry`] for as long as its response is in flight.
+#[derive(Clone)]
+pub struct RegistryLayer {
+    registry: Registry,
+}
+
+impl RegistryLayer {
+    pub fn new(registry: Registry) -> Self {
+        Self { registry }
+    }
+}
+
+impl<S> Layer<S> for RegistryLayer {
+    type Service = RegistryService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RegistryService {
+            inner,
+            registry: self.registry.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RegistryService<S> {
+    inner: S,
+    registry: Registry,
+}
+
+impl<S, ReqBody> Service<http::Request<ReqBody>> for RegistryService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
+        let peer = req
+            .extensions()
+            .get::<TcpConnectInfo>()
+            .and_then(|info| info.remote_addr());
+        let guard = self.registry.open_stream(req.uri().path(), peer);
+        let cancel = self.registry.cancel.clone();
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let resp = tokio::select! {
+                resp = fut => resp?,
+                () = cancel.cancelled() => return Ok(shutting_down().into_http()),
+            };
+            Ok(resp.map(|body| {
+                tonic::body::boxed(TrackedBody {
+                    inner: Some(body),
+                    cancelled: Box::pin(cancel.cancelled_owned()),
+                    _guard: guard,
+                })
+            }))
+        })
+    }
+}
+
+fn shutting_down() -> Status {
+    Status::unavailable("server shutting down")
+}
+
+/// A response body that keeps its stream registered until it is dropped, and that ends with an `UNAVAILABLE` status
+/// as soon as the registry cancels it.
+struct TrackedBody {
+    /// `None` once we have produced trailers, either the inner body's or our own.
+    inner: Option<BoxBody>,
+    cancelled: Pin<Box<WaitForCancellationFutureOwned>>,
+    _guard: Guard,
+}
+
+impl Body for TrackedBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        let Some(inner) = this.inner.as_mut() else {
+            return Poll::Ready(None);
+        };
+        if this.cancelled.as_mut().poll(cx).is_ready() {
+            this.inner = None;
+            let mut trailers = shutting_down().into_http().into_parts().0.headers;
+            trailers.remove(http::header::CONTENT_TYPE);
+            return Poll::Ready(Some(Ok(Frame::trailers(trailers))));
+        }
+        let frame = std::task::ready!(Pin::new(inner).poll_frame(cx));
+        if let Some(Ok(frame)) = &frame {
+            if frame.is_trailers() {
+                this.inner = None;
+            }
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.as_ref().is_none_or(Body::is_end_stream)
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner
+            .as_ref()
+            .map_or_else(|| SizeHint::with_exact(0), Body::size_hint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use http_body_util::{BodyExt, Empty, StreamBody};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    const WATCH: &str = "/grpc.health.v1.Health/Watch";
+
+    fn registry() -> Registry {
+        let registry = Registry::new();
+        registry
+            .register_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+            .unwrap();
+        registry
+    }
+
+    fn request(path: &str) -> http::Request<()> {
+        http::Request::builder().uri(path).body(()).unwrap()
+    }
+
+    #[test]
+    fn tracks_connections_until_closed() {
+        let registry = registry();
+        let peer = SocketAddr::from(([127, 0, 0, 1], 1234));
+        let first = registry.track((), Some(peer));
+        let second = registry.track((), None);
+        let connections = registry.connections();
+        assert_eq!(connections.len(), 2);
+        assert_eq!(connections[0].peer, Some(peer));
+        drop(first);
+        assert_eq!(registry.connections().len(), 1);
+        drop(second);
+        assert!(registry.connections().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tracks_streams_until_their_response_is_done() {
+        let registry = registry();
+        let service = RegistryLayer::new(registry.clone()).layer(tower::service_fn(
+            |_: http::Request<()>| async {
+                Ok::<_, Infallible>(http::Response::new(tonic::body::boxed(
+                    Empty::<Bytes>::new(),
+                )))
+            },
+        ));
+        let _connection = registry.track((), None);
+
+        let response = service.clone().oneshot(request(WATCH)).await.unwrap();
+        let streams = registry.streams();
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].method, WATCH);
+        assert_eq!(streams[0].kind, StreamKind::ServerStreaming);
+        assert_eq!(streams[0].short_method(), "Health/Watch");
+        assert!(registry
+            .summary(5)
+            .starts_with("1 stream remaining on 1 connection: Health/Watch (server streaming)"));
+        drop(response);
+        assert!(registry.streams().is_empty());
+
+        // Methods without a registered descriptor are still tracked, just without a kind.
+        let _response = service.oneshot(request("/a.B/C")).await.unwrap();
+        assert_eq!(registry.streams()[0].kind, StreamKind::Unknown);
+        assert!(registry.summary(0).starts_with("1 stream remaining"));
+    }
+
+    #[tokio::test]
+    async fn cancel_all_ends_streams_with_unavailable() {
+        let registry = registry();
+        // One handler that never responds, and one whose response never ends.
+        let service = RegistryLayer::new(registry.clone()).layer(tower::service_fn(
+            |req: http::Request<()>| async move {
+                if req.uri().path() == WATCH {
+                    std::future::pending::<()>().await;
+                }
+                let body = StreamBody::new(tokio_stream::pending::<Result<Frame<Bytes>, Status>>());
+                Ok::<_, Infallible>(http::Response::new(tonic::body::boxed(body)))
+            },
+        ));
+        let unanswered = tokio::spawn(service.clone().oneshot(request(WATCH)));
+        let mut response = service.clone().oneshot(request("/a.B/C")).await.unwrap();
+        while registry.streams().len() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        assert_eq!(registry.cancel_all(), 2);
+        let unanswered = unanswered.await.unwrap().unwrap();
+        let status = Status::from_header_map(unanswered.headers()).unwrap();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        let trailers = response
+            .body_mut()
+            .frame()
+            .await
+            .unwrap()
+            .unwrap()
+            .into_trailers()
+            .unwrap();
+        let status = Status::from_header_map(&trailers).unwrap();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert!(response.body_mut().frame().await.is_none());
+        drop(response);
+        assert!(registry.streams().is_empty());
+
+        // Streams that open afterwards are ended right away.
+        let late = service.oneshot(request(WATCH)).await.unwrap();
+        let status = Status::from_header_map(late.headers()).unwrap();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+    }
+}
@@ -0,0 +1,1423 @@
+use std::{
+    convert::Infallible,
+    io,
+    net::SocketAddr,
+    os::fd::{AsRawFd, RawFd},
+    pin::Pin,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use tokio::{task::JoinSet, time::Instant};
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::sync::CancellationToken;
+use tonic::{
+    body::BoxBody,
+    codegen::{http, Service},
+    server::NamedService,
+    service::RoutesBuilder,
+    transport::Server,
+};
+use tonic_health::server::HealthReporter;
+use tracing::{error, info, warn};
+
+use crate::{
+    admin::{self, pb::admin_server::AdminServer, AdminService},
+    checks::Checker,
+    health::{HealthOverride, ServiceStatuses},
+    lifecycle::{Lifecycle, Phase, ShutdownReason},
+    listener::{Conn, Incoming, ListenAddr, Listener},
+    metrics::{self, Metrics, MetricsLayer, ScrapeLayer},
+    probes,
+    registry::{Registry, RegistryLayer},
+    signal::{Signal, Signals},
+    systemd::{self, Notifier},
+    tls::{self, TlsAcceptor, TlsConfig},
+    token::ShutdownTokenLayer,
+    upgrade,
+};
+
+/// Exit code used when a third signal arrives while we are already forcing the shutdown.
+pub const ABORT_EXIT_CODE: i32 = 3;
+
+/// Builds a tonic server that knows how to shut itself down.
+///
+/// Once a shutdown is requested (via [`ServerHandle::shutdown`] or, if enabled, a signal) the server stops accepting
+/// connections, asks existing ones to stop sending requests, and waits for live streams to wrap up. If they have not
+/// by the end of the grace period, the server gives up on them.
+pub struct GracefulServer {
+    server: Server,
+    routes: RoutesBuilder,
+    lame_duck: Duration,
+    grace_period: Option<Duration>,
+    health_reporter: Option<HealthReporter>,
+    health_drain_order: Vec<String>,
+    health_drain_step: Duration,
+    health_checks: Vec<Checker>,
+    health_overrides: Vec<HealthOverride>,
+    /// The names of the services added with [`GracefulServer::add_service`], for their health statuses.
+    services: Vec<&'static str>,
+    handle_signals: bool,
+    max_connections: Option<usize>,
+    progress_interval: Duration,
+    force_close_timeout: Duration,
+    upgrade_timeout: Duration,
+    admin: Admin,
+    admin_routes: RoutesBuilder,
+    admin_file_descriptor_sets: Vec<&'static [u8]>,
+    metrics: Option<HttpListener>,
+    probes: Option<HttpListener>,
+    final_scrape_timeout: Duration,
+    tls: Option<TlsConfig>,
+    notifier: Option<Notifier>,
+    reload_hooks: Vec<ReloadHook>,
+    warmups: Vec<Warmup>,
+    startup_timeout: Option<Duration>,
+    registry: Registry,
+    lifecycle: Lifecycle,
+}
+
+type ReloadHook = Box<dyn Fn(&ServerHandle) + Send + Sync>;
+type Warmup = Pin<Box<dyn std::future::Future<Output = anyhow::Result<()>> + Send>>;
+
+/// Where to serve one of the plain HTTP servers, [`crate::metrics`] or the probes.
+enum HttpListener {
+    Bind(SocketAddr),
+    Inherited(Listener),
+}
+
+impl HttpListener {
+    async fn bind(self) -> anyhow::Result<Listener> {
+        match self {
+            HttpListener::Bind(address) => Listener::bind(&address.into())
+                .await
+                .with_context(|| format!("failed to bind {address}")),
+            HttpListener::Inherited(listener) => Ok(listener),
+        }
+    }
+}
+
+/// Where (and whether) to serve the [`crate::admin`] service.
+enum Admin {
+    Disabled,
+    /// Next to the other services, on the main listener.
+    Shared,
+    /// On a listener of its own.
+    Dedicated(SocketAddr),
+    /// On a listener of its own that is already bound.
+    Inherited(Listener),
+}
+
+impl GracefulServer {
+    pub fn builder() -> Self {
+        let lifecycle = Lifecycle::new();
+        let registry = Registry::new();
+        lifecycle.on_transition({
+            let registry = registry.clone();
+            move |prev, next| crate::lifecycle::log_transition(prev, next, &registry)
+        });
+        Self {
+            server: Server::builder(),
+            routes: RoutesBuilder::default(),
+            lame_duck: Duration::ZERO,
+            grace_period: None,
+            health_reporter: None,
+            health_drain_order: Vec::new(),
+            health_drain_step: Duration::ZERO,
+            health_checks: Vec::new(),
+            health_overrides: Vec::new(),
+            services: Vec::new(),
+            handle_signals: false,
+            max_connections: None,
+            progress_interval: Duration::from_secs(5),
+            force_close_timeout: Duration::from_secs(1),
+            upgrade_timeout: Duration::from_secs(30),
+            admin: Admin::Disabled,
+            admin_routes: RoutesBuilder::default(),
+            admin_file_descriptor_sets: Vec::new(),
+            metrics: None,
+            probes: None,
+            final_scrape_timeout: Duration::from_secs(5),
+            tls: None,
+            notifier: None,
+            reload_hooks: Vec::new(),
+            warmups: Vec::new(),
+            startup_timeout: None,
+            registry,
+            lifecycle,
+        }
+    }
+
+    /// The lifecycle the server will drive, for services that want to react to it (like
+    /// [`crate::health::health_reporter`]).
+    pub fn lifecycle(&self) -> &Lifecycle {
+        &self.lifecycle
+    }
+
+    /// Tweak the underlying tonic server, e.g. to set timeouts or HTTP/2 options.
+    pub fn configure(mut self, f: impl FnOnce(Server) -> Server) -> Self {
+        self.server = f(self.server);
+        self
+    }
+
+    pub fn add_service<S>(mut self, svc: S) -> Self
+    where
+        S: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>, Error = Infallible>
+            + NamedService
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send + 'static,
+    {
+        self.services.push(S::NAME);
+        self.routes.add_service(svc);
+        self
+    }
+
+    /// How long to keep accepting connections after a shutdown is requested, while reporting `NOT_SERVING`. This gives
+    /// load balancers time to notice the health change before we start refusing connections. Defaults to zero.
+    pub fn lame_duck(mut self, lame_duck: Duration) -> Self {
+        self.lame_duck = lame_duck;
+        self
+    }
+
+    /// How long to wait for live streams after a shutdown is requested, lame-duck period included. `None` (the
+    /// default) waits forever.
+    pub fn grace_period(mut self, grace_period: Option<Duration>) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Report the health of the server, and of every service added to it, through `health_reporter`: `NOT_SERVING`
+    /// until the server serves (even before the first connection is accepted), `SERVING` while it does, and
+    /// `NOT_SERVING` again as soon as the drain starts, to discourage clients from sending us anything new. See
+    /// [`ServiceStatuses`].
+    pub fn health_reporter(mut self, health_reporter: HealthReporter) -> Self {
+        self.health_reporter = Some(health_reporter);
+        self
+    }
+
+    /// The order in which services go `NOT_SERVING` when the drain starts, by their full names (e.g.
+    /// `grpc.health.v1.Health`, or the empty name for the server as a whole). Services left out follow in the order
+    /// they were added, then the server as a whole. Naming a service that is not served fails
+    /// [`GracefulServer::serve`].
+    pub fn health_drain_order(mut self, health_drain_order: Vec<String>) -> Self {
+        self.health_drain_order = health_drain_order;
+        self
+    }
+
+    /// How long to wait between one service going `NOT_SERVING` and the next, so that clients can move off them one
+    /// at a time. Defaults to zero.
+    pub fn health_drain_step(mut self, health_drain_step: Duration) -> Self {
+        self.health_drain_step = health_drain_step;
+        self
+    }
+
+    /// Run `checker` while the server runs, and report the services it gates as `NOT_SERVING` while it fails. See
+    /// [`crate::checks`]. Needs a [`GracefulServer::health_reporter`].
+    pub fn health_check(mut self, checker: Checker) -> Self {
+        self.health_checks.push(checker);
+        self
+    }
+
+    /// Pin `service`'s health status to `status` from the start, as [`ServiceStatuses::set_override`] would. Naming a
+    /// service that is not served fails [`GracefulServer::serve`]. Needs a [`GracefulServer::health_reporter`].
+    pub fn health_override(
+        mut self,
+        service: impl Into<String>,
+        status: tonic_health::ServingStatus,
+    ) -> Self {
+        self.health_overrides.push(HealthOverride {
+            service: service.into(),
+            status,
+        });
+        self
+    }
+
+    /// How many connections the main listeners may have open at once. Connections over the limit are closed as soon
+    /// as they are accepted, so that clients go elsewhere or retry later. `None` (the default) means no limit. See
+    /// [`ServerHandle::set_max_connections`].
+    pub fn max_connections(mut self, max_connections: Option<usize>) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Shut down on SIGTERM, SIGINT or SIGQUIT. A second signal skips the rest of the grace period, and a third
+    /// aborts the process with [`ABORT_EXIT_CODE`]. SIGUSR2 starts a [`ServerHandle::upgrade`].
+    pub fn shutdown_on_signals(mut self) -> Self {
+        self.handle_signals = true;
+        self
+    }
+
+    /// Teach the server which methods are streaming, so that its reports on what is blocking a shutdown can say so.
+    /// `encoded` is a `FileDescriptorSet`, like the ones you register with `tonic_reflection`.
+    pub fn register_file_descriptor_set(self, encoded: &[u8]) -> anyhow::Result<Self> {
+        self.registry.register_file_descriptor_set(encoded)?;
+        Ok(self)
+    }
+
+    /// How often to log which streams are still open while draining. Defaults to 5s.
+    pub fn progress_interval(mut self, progress_interval: Duration) -> Self {
+        self.progress_interval = progress_interval;
+        self
+    }
+
+    /// Once the grace period is over, every remaining stream is ended with an `UNAVAILABLE` status. This is how long
+    /// we then wait for those statuses to reach the clients and the connections to close. Defaults to 1s.
+    pub fn force_close_timeout(mut self, force_close_timeout: Duration) -> Self {
+        self.force_close_timeout = force_close_timeout;
+        self
+    }
+
+    /// Serve the [`crate::admin`] service, which lets operators trigger, inspect and cancel a drain over gRPC.
+    ///
+    /// With an `address`, it gets a listener of its own, which stays open while the main listener drains and only
+    /// closes once the server has stopped. Next to the admin service, that listener serves a reflection service, the
+    /// services added with [`GracefulServer::add_admin_service`], and [`crate::metrics`] at `/metrics` (over HTTP/1).
+    /// If `/metrics` has been scraped there, the listener also waits for a final scrape before closing, like
+    /// [`GracefulServer::metrics`] does. Streams still open on it then are ended with `UNAVAILABLE`, and their
+    /// connections get the [`GracefulServer::force_close_timeout`] to close.
+    ///
+    /// Without an `address`, the admin service is served on the main listener next to the other services, so new
+    /// connections to it are refused as soon as the drain starts. Remember to register
+    /// [`admin::pb::FILE_DESCRIPTOR_SET`] with your reflection service in that case.
+    pub fn admin_service(mut self, address: Option<SocketAddr>) -> Self {
+        self.admin = match address {
+            Some(address) => Admin::Dedicated(address),
+            None => Admin::Shared,
+        };
+        self
+    }
+
+    /// Like [`GracefulServer::admin_service`] with an address, but serve the admin service on a listener that is
+    /// already bound, e.g. one handed over by [`crate::upgrade::Handover`].
+    pub fn admin_listener(mut self, listener: Listener) -> Self {
+        self.admin = Admin::Inherited(listener);
+        self
+    }
+
+    /// Also serve `svc` on the admin service's own listener, so that it stays reachable until the server has stopped;
+    /// typically the health service, so that probes see `NOT_SERVING` rather than a refused connection. Ignored
+    /// unless [`GracefulServer::admin_service`] was given an address. Unlike [`GracefulServer::add_service`], RPCs to
+    /// it are neither tracked nor counted in the metrics.
+    pub fn add_admin_service<S>(mut self, svc: S) -> Self
+    where
+        S: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>, Error = Infallible>
+            + NamedService
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send + 'static,
+    {
+        self.admin_routes.add_service(svc);
+        self
+    }
+
+    /// Register `encoded`, a `FileDescriptorSet`, with the reflection service on the admin service's own listener, for
+    /// the services added with [`GracefulServer::add_admin_service`].
+    pub fn register_admin_file_descriptor_set(mut self, encoded: &'static [u8]) -> Self {
+        self.admin_file_descriptor_sets.push(encoded);
+        self
+    }
+
+    /// How long [`ServerHandle::upgrade`] waits for the new process to become ready before giving up on it. Defaults
+    /// to 30s.
+    pub fn upgrade_timeout(mut self, upgrade_timeout: Duration) -> Self {
+        self.upgrade_timeout = upgrade_timeout;
+        self
+    }
+
+    /// Serve Prometheus metrics over HTTP at `/metrics` on `address`, see [`crate::metrics`]. The listener stays open
+    /// after the server stops, until one more scrape has picked up the final values (see
+    /// [`GracefulServer::final_scrape_timeout`]).
+    pub fn metrics(mut self, address: Option<SocketAddr>) -> Self {
+        self.metrics = address.map(HttpListener::Bind);
+        self
+    }
+
+    /// Like [`GracefulServer::metrics`], but on a listener that is already bound, e.g. one handed over by
+    /// [`crate::upgrade::Handover`].
+    pub fn metrics_listener(mut self, listener: Listener) -> Self {
+        self.metrics = Some(HttpListener::Inherited(listener));
+        self
+    }
+
+    /// Serve the HTTP endpoints for Kubernetes probes and `preStop` hooks on `address`. `/livez` answers 200 for as
+    /// long as the process is up; `/readyz` answers 200 while serving and 503 from the moment a shutdown begins; and a
+    /// GET or POST to `/prestop` starts a shutdown and only answers once the main listeners have closed. The listener
+    /// stays open until the server has stopped.
+    pub fn probes(mut self, address: Option<SocketAddr>) -> Self {
+        self.probes = address.map(HttpListener::Bind);
+        self
+    }
+
+    /// Like [`GracefulServer::probes`], but on a listener that is already bound, e.g. one handed over by
+    /// [`crate::upgrade::Handover`].
+    pub fn probes_listener(mut self, listener: Listener) -> Self {
+        self.probes = Some(HttpListener::Inherited(listener));
+        self
+    }
+
+    /// How long [`ServerHandle::wait`] waits for a final scrape of the metrics after the server has stopped. Defaults
+    /// to 5s.
+    pub fn final_scrape_timeout(mut self, final_scrape_timeout: Duration) -> Self {
+        self.final_scrape_timeout = final_scrape_timeout;
+        self
+    }
+
+    /// Serve the main listeners over TLS, see [`crate::tls`]. The admin service's own listener stays plaintext.
+    ///
+    /// The certificates are loaded when the server starts, which fails if they are invalid, and reloaded whenever
+    /// their files change, or on [`ServerHandle::reload_tls`].
+    pub fn tls(mut self, tls: Option<TlsConfig>) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Report readiness, shutdown progress and watchdog pings to the service manager, see [`crate::systemd`].
+    pub fn notify_systemd(mut self, notifier: Option<Notifier>) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    /// Register a hook that is called on [`ServerHandle::reload`] (and so on SIGHUP), to re-read settings and apply
+    /// them with setters like [`ServerHandle::set_grace_period`].
+    pub fn on_reload(mut self, hook: impl Fn(&ServerHandle) + Send + Sync + 'static) -> Self {
+        self.reload_hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Run `warmup` (e.g. filling a cache) once the listeners are bound, and only start serving once it, and every
+    /// other warmup, has succeeded. Until then the server stays [`Phase::Starting`]: it accepts connections, but
+    /// reports `NOT_SERVING`. If a warmup fails, the server shuts down without ever serving, see
+    /// [`ShutdownReason::Startup`]. Streams opened in the meantime get the grace period or the startup timeout,
+    /// whichever is shorter, to finish; without a startup timeout, they are ended right away.
+    pub fn warmup(
+        mut self,
+        warmup: impl std::future::Future<Output = anyhow::Result<()>> + Send + 'static,
+    ) -> Self {
+        self.warmups.push(Box::pin(warmup));
+        self
+    }
+
+    /// How long the warmups may take, all together, before they count as failed. `None` (the default) waits forever.
+    pub fn startup_timeout(mut self, startup_timeout: Option<Duration>) -> Self {
+        self.startup_timeout = startup_timeout;
+        self
+    }
+
+    /// Register a hook that is called on every lifecycle transition. See [`Lifecycle::on_transition`].
+    pub fn on_transition(self, hook: impl Fn(&Phase, &Phase) + Send + Sync + 'static) -> Self {
+        self.lifecycle.on_transition(hook);
+        self
+    }
+
+    /// Bind to every one of `addresses` and start serving on all of them in the background. With
+    /// [`GracefulServer::warmup`]s, the server only reports `SERVING` once they are done; see
+    /// [`ServerHandle::ready`].
+    pub async fn serve(self, addresses: &[ListenAddr]) -> anyhow::Result<ServerHandle> {
+        if addresses.is_empty() {
+            anyhow::bail!("no addresses to serve on");
+        }
+        self.serve_inner(async {
+            let mut listeners = Vec::with_capacity(addresses.len());
+            for address in addresses {
+                let listener = Listener::bind(address)
+                    .await
+                    .with_context(|| format!("failed to bind {address}"))?;
+                listeners.push(listener);
+            }
+            Ok(listeners)
+        })
+        .await
+    }
+
+    /// Start serving on listeners that are already bound, e.g. ones from [`Listener::from_systemd`].
+    pub async fn serve_listeners(self, listeners: Vec<Listener>) -> anyhow::Result<ServerHandle> {
+        if listeners.is_empty() {
+            anyhow::bail!("no listeners to serve on");
+        }
+        self.serve_inner(async { Ok(listeners) }).await
+    }
+
+    async fn serve_inner(
+        self,
+        listeners: impl std::future::Future<Output = anyhow::Result<Vec<Listener>>>,
+    ) -> anyhow::Result<ServerHandle> {
+        let Self {
+            server,
+            mut routes,
+            lame_duck,
+            grace_period,
+            health_reporter,
+            health_drain_order,
+            health_drain_step,
+            health_checks,
+            health_overrides,
+            mut services,
+            handle_signals,
+            max_connections,
+            progress_interval,
+            force_close_timeout,
+            upgrade_timeout,
+            admin,
+            admin_routes,
+            admin_file_descriptor_sets,
+            metrics,
+            probes,
+            final_scrape_timeout,
+            tls,
+            notifier,
+            reload_hooks,
+            warmups,
+            startup_timeout,
+            registry,
+            lifecycle,
+        } = self;
+
+        // Install the signal handlers before binding, so that a signal can never hit the default handler while
+        // clients are able to connect. Signals that arrive before the task below starts are not lost.
+        let signals = handle_signals.then(Signals::new).transpose()?;
+        let tls = tls
+            .map(TlsAcceptor::new)
+            .transpose()
+            .context("failed to load TLS certificates")?;
+        if matches!(admin, Admin::Shared) {
+            services.push(<AdminServer<AdminService> as NamedService>::NAME);
+        }
+        let health = health_reporter
+            .map(|reporter| ServiceStatuses::new(reporter, &services, &health_drain_order))
+            .transpose()?;
+        // `tonic_health` starts the empty service name off as `SERVING`, so report before anything can ask.
+        if let Some(health) = &health {
+            health.report_all().await;
+        }
+        if !health_checks.is_empty() {
+            let health = health
+                .as_ref()
+                .context("health checks need a health reporter")?;
+            for checker in &health_checks {
+                health.ensure_known(checker.gated_services()).await?;
+            }
+        }
+        if !health_overrides.is_empty() {
+            let health = health
+                .as_ref()
+                .context("health overrides need a health reporter")?;
+            for HealthOverride { service, status } in health_overrides {
+                health.set_override(&service, status).await?;
+            }
+        }
+
+        let listeners = listeners.await?;
+        let mut handover = listeners
+            .iter()
+            .map(|listener| {
+                let remove_on_stop = listener.socket_path().is_some();
+                (listener.as_raw_fd(), "grpc", remove_on_stop)
+            })
+            .collect::<Vec<_>>();
+        let socket_paths: Vec<_> = listeners
+            .iter()
+            .filter_map(|listener| listener.socket_path().map(ToOwned::to_owned))
+            .collect();
+        if !socket_paths.is_empty() {
+            lifecycle.on_transition(move |_, next| {
+                // After an upgrade, the new process is still serving on these sockets.
+                if let Phase::Stopped { reason, .. } = next {
+                    if !matches!(reason, ShutdownReason::Upgrade(_)) {
+                        remove_socket_files(&socket_paths);
+                    }
+                }
+            });
+        }
+        let local_addrs = listeners
+            .iter()
+            .map(Listener::local_addr)
+            .collect::<io::Result<_>>()?;
+        let incoming: Pin<Box<dyn Stream<Item = Conn> + Send>> = match &tls {
+            Some(tls) => Box::pin(tls.accept(Incoming::new(listeners))),
+            None => Box::pin(Incoming::new(listeners)),
+        };
+        let max_connections = Arc::new(RwLock::new(max_connections));
+        let incoming = incoming.filter_map({
+            let registry = registry.clone();
+            let max_connections = max_connections.clone();
+            let mut at_limit = false;
+            move |io: Conn| {
+                let peer = io.peer_addr();
+                let limit = *max_connections.read().unwrap();
+                match limit {
+                    Some(limit) if registry.connections().len() >= limit => {
+                        if !std::mem::replace(&mut at_limit, true) {
+                            warn!(
+                                max_connections = limit,
+                                "connection limit reached, closing new connections until some close"
+                            );
+                        }
+                        // Dropping the connection closes it.
+                        return None;
+                    }
+                    _ => {
+                        if std::mem::replace(&mut at_limit, false) {
+                            info!("back under the connection limit, accepting new connections");
+                        }
+                    }
+                }
+                Some(Ok::<_, io::Error>(registry.track(io, peer)))
+            }
+        });
+        let (shared_admin, admin_listener) = match admin {
+            Admin::Disabled => (false, None),
+            Admin::Shared => (true, None),
+            Admin::Dedicated(address) => (false, Some(Listener::bind(&address.into()).await?)),
+            Admin::Inherited(listener) => (false, Some(listener)),
+        };
+        if let Some(listener) = &admin_listener {
+            handover.push((listener.as_raw_fd(), "admin", false));
+        }
+        let metrics_listener = match metrics {
+            Some(metrics) => Some(metrics.bind().await?),
+            None => None,
+        };
+        if let Some(listener) = &metrics_listener {
+            handover.push((listener.as_raw_fd(), "metrics", false));
+        }
+        let probes_listener = match probes {
+            Some(probes) => Some(probes.bind().await?),
+            None => None,
+        };
+        if let Some(listener) = &probes_listener {
+            handover.push((listener.as_raw_fd(), "probes", false));
+        }
+        let metrics = Metrics::new(registry.clone(), lifecycle.clone(), &services);
+
+        let handle = ServerHandle {
+            lifecycle: lifecycle.clone(),
+            registry: registry.clone(),
+            periods: Arc::new(RwLock::new(Periods {
+                lame_duck,
+                grace_period,
+            })),
+            max_connections,
+            local_addrs,
+            admin_addr: admin_listener
+                .as_ref()
+                .map(Listener::local_addr)
+                .transpose()?,
+            metrics_addr: metrics_listener
+                .as_ref()
+                .map(Listener::local_addr)
+                .transpose()?,
+            probes_addr: probes_listener
+                .as_ref()
+                .map(Listener::local_addr)
+                .transpose()?,
+            metrics: metrics.clone(),
+            metrics_done: CancellationToken::new(),
+            admin_done: CancellationToken::new(),
+            handover: Arc::new(handover),
+            upgrade_timeout,
+            upgrading: Arc::new(tokio::sync::Mutex::new(())),
+            tls: tls.clone(),
+            health: health.clone(),
+            health_checks: Default::default(),
+            reload_hooks: Arc::new(reload_hooks),
+        };
+        if health.is_some() {
+            handle.set_health_checks(health_checks).await?;
+        }
+        if shared_admin {
+            routes.add_service(AdminService::new(handle.clone()));
+        }
+        match admin_listener {
+            Some(listener) => {
+                let served = serve_admin(
+                    listener,
+                    handle.clone(),
+                    admin_routes,
+                    &admin_file_descriptor_sets,
+                    final_scrape_timeout,
+                    force_close_timeout,
+                )?;
+                let done = handle.admin_done.clone().drop_guard();
+                tokio::spawn(async move {
+                    served.await;
+                    drop(done);
+                });
+            }
+            None => handle.admin_done.cancel(),
+        }
+        match metrics_listener {
+            Some(listener) => {
+                let done = handle.metrics_done.clone().drop_guard();
+                tokio::spawn(async move {
+                    metrics::serve(listener, metrics, final_scrape_timeout).await;
+                    drop(done);
+                });
+            }
+            None => handle.metrics_done.cancel(),
+        }
+        if let Some(listener) = probes_listener {
+            tokio::spawn(probes::serve(listener, handle.clone()));
+        }
+        if let Some(signals) = signals {
+            tokio::spawn(handle_signals_task(signals, handle.clone()));
+        }
+
+        // This future will resolve when the server shuts down organically (either via a graceful
+        // serve_with_incoming_shutdown or by encountering an error).
+        let organic = tokio::spawn({
+            let mut phase = lifecycle.subscribe();
+            server
+                .layer(MetricsLayer::new(handle.metrics.clone()))
+                .layer(RegistryLayer::new(registry.clone()))
+                .layer(ShutdownTokenLayer::new(&lifecycle))
+                .add_routes(routes.routes())
+                .serve_with_incoming_shutdown(incoming, async move {
+                    // The shutdown can still be aborted during the lame-duck period, so wait for the drain proper.
+                    let _ = phase.wait_for(Phase::is_draining).await;
+                    info!("no longer accepting new connections");
+                })
+        });
+        if let Some(health) = health {
+            tokio::spawn(health.follow(lifecycle.subscribe(), health_drain_step));
+        }
+        if let Some(tls) = tls {
+            tokio::spawn(tls::reload_on_change(tls, lifecycle.subscribe()));
+        }
+        if let Some(notifier) = notifier {
+            tokio::spawn(systemd::notify_lifecycle(
+                notifier,
+                registry.clone(),
+                lifecycle.subscribe(),
+                force_close_timeout,
+            ));
+        }
+        if warmups.is_empty() {
+            lifecycle.serving();
+        } else {
+            tokio::spawn(warm_up(warmups, startup_timeout, handle.clone()));
+        }
+
+        lifecycle.on_transition({
+            let registry = registry.clone();
+            move |_, next| {
+                if let Phase::ForceClosing { .. } = next {
+                    let streams = registry.streams();
+                    warn!(
+                        remaining_streams = streams.len(),
+                        "{}:",
+                        registry.summary(0)
+                    );
+                    for stream in streams {
+                        warn!(
+                            method = stream.method,
+                            peer = stream.peer.map(|peer| peer.to_string()),
+                            open_ms = stream.started_at.elapsed().as_millis() as u64,
+                            "  {stream}"
+                        );
+                    }
+                }
+            }
+        });
+        tokio::spawn(report_progress(
+            registry.clone(),
+            lifecycle.subscribe(),
+            progress_interval,
+        ));
+        tokio::spawn(supervise(
+            organic,
+            registry.clone(),
+            lifecycle.clone(),
+            force_close_timeout,
+        ));
+        Ok(handle)
+    }
+}
+
+fn remove_socket_files(paths: &[std::path::PathBuf]) {
+    for path in paths {
+        if let Err(err) = std::fs::remove_file(path) {
+            warn!("failed to remove socket file {}: {err}", path.display());
+        }
+    }
+}
+
+/// Serve the admin service, and `routes` next to it, on a listener of its own. It stays open until the main server has
+/// stopped, and then until the final scrape of the metrics if anyone has been scraping them here.
+///
+/// Streams that are still open then, like health `Watch`es from a load balancer, are ended with `UNAVAILABLE`, and
+/// their connections get `force_close_timeout` to close before we give up on them, so that they cannot keep the
+/// process alive.
+fn serve_admin(
+    listener: Listener,
+    handle: ServerHandle,
+    mut routes: RoutesBuilder,
+    file_descriptor_sets: &[&'static [u8]],
+    final_scrape_timeout: Duration,
+    force_close_timeout: Duration,
+) -> anyhow::Result<impl std::future::Future<Output = ()>> {
+    let reflection_service = file_descriptor_sets
+        .iter()
+        .fold(
+            tonic_reflection::server::Builder::configure()
+                .register_encoded_file_descriptor_set(admin::pb::FILE_DESCRIPTOR_SET),
+            |builder, encoded| builder.register_encoded_file_descriptor_set(encoded),
+        )
+        .build_v1()?;
+    routes
+        .add_service(AdminService::new(handle.clone()))
+        .add_service(reflection_service);
+    let incoming = Incoming::new(vec![listener]).map(Ok::<_, io::Error>);
+    let metrics = handle.metrics.clone();
+    // Separate from the main server's, so that admin streams neither block its drain nor show up in its metrics.
+    let registry = Registry::new();
+    let closing = CancellationToken::new();
+    Ok(async move {
+        let served = Server::builder()
+            // For `/metrics`.
+            .accept_http1(true)
+            .layer(ScrapeLayer::new(metrics.clone()))
+            .layer(RegistryLayer::new(registry.clone()))
+            .add_routes(routes.routes())
+            .serve_with_incoming_shutdown(incoming, {
+                let closing = closing.clone();
+                async move {
+                    let _ = handle
+                        .lifecycle
+                        .subscribe()
+                        .wait_for(|phase| matches!(phase, Phase::Stopped { .. }))
+                        .await;
+                    if metrics.scraped() {
+                        metrics.final_scrape(final_scrape_timeout).await;
+                    }
+                    closing.cancel();
+                }
+            });
+        tokio::pin!(served);
+        let served = tokio::select! {
+            served = &mut served => served,
+            () = closing.cancelled() => {
+                // The server has stopped, so there is nothing left for these streams to wait for.
+                let cancelled = registry.cancel_all();
+                if cancelled > 0 {
+                    info!(
+                        cancelled_streams = cancelled,
+                        "ended admin streams with UNAVAILABLE, waiting for their connections to close"
+                    );
+                }
+                match tokio::time::timeout(force_close_timeout, served).await {
+                    Ok(served) => served,
+                    Err(_) => {
+                        warn!(
+                            remaining_streams = registry.streams().len(),
+                            "admin connections did not close, giving up on them"
+                        );
+                        return;
+                    }
+                }
+            }
+        };
+        if let Err(err) = served {
+            error!("admin server failed: {err:#}");
+        }
+    })
+}
+
+/// Run `warmups` concurrently, and start serving once they have all succeeded. If one fails, or they are not done
+/// within `timeout`, shut down instead. A shutdown that begins in the meantime cancels them for good: it cannot be
+/// aborted (see [`Lifecycle::abort_shutdown`]), since the server would then serve without having warmed up.
+async fn warm_up(warmups: Vec<Warmup>, timeout: Option<Duration>, handle: ServerHandle) {
+    info!(
+        warmups = warmups.len(),
+        "warming up, reporting NOT_SERVING until done"
+    );
+    let mut phase = handle.lifecycle.subscribe();
+    let mut tasks = JoinSet::new();
+    for warmup in warmups {
+        tasks.spawn(warmup);
+    }
+    let all = async {
+        while let Some(result) = tasks.join_next().await {
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => return Err(format!("{err:#}")),
+                Err(err) => return Err(format!("warmup failed: {err}")),
+            }
+        }
+        Ok(())
+    };
+    let all = async {
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, all)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(format!("warmup not done within {}ms", timeout.as_millis()))
+                }),
+            None => all.await,
+        }
+    };
+    tokio::select! {
+        result = all => match result {
+            Ok(()) => {
+                handle.lifecycle.serving();
+            }
+            // Clients may have connected in the meantime, so this drains like any other shutdown. But a failed start
+            // must not hang around for streams (such as health `Watch`es) that may never end, so the drain gets no
+            // longer than the warmups did, and none at all without a startup timeout.
+            Err(msg) => {
+                let grace_period = [handle.grace_period(), timeout]
+                    .into_iter()
+                    .flatten()
+                    .min()
+                    .unwrap_or(Duration::ZERO);
+                handle.lifecycle.begin_shutdown(
+                    ShutdownReason::Startup(msg),
+                    Duration::ZERO,
+                    Some(grace_period),
+                );
+            }
+        },
+        _ = phase.wait_for(Phase::is_shutting_down) => {}
+    }
+}
+
+/// A running [`GracefulServer`].
+#[derive(Clone)]
+pub struct ServerHandle {
+    lifecycle: Lifecycle,
+    registry: Registry,
+    periods: Arc<RwLock<Periods>>,
+    max_connections: Arc<RwLock<Option<usize>>>,
+    local_addrs: Vec<ListenAddr>,
+    admin_addr: Option<ListenAddr>,
+    metrics_addr: Option<ListenAddr>,
+    probes_addr: Option<ListenAddr>,
+    metrics: Metrics,
+    /// Cancelled once the metrics listener has closed, or right away if there is none.
+    metrics_done: CancellationToken,
+    /// Cancelled once the admin service's own listener has closed, or right away if there is none.
+    admin_done: CancellationToken,
+    /// The listeners to pass on to the new process in [`ServerHandle::upgrade`], their names, and whether the new
+    /// process should remove their socket files when it stops.
+    handover: Arc<Vec<(RawFd, &'static str, bool)>>,
+    upgrade_timeout: Duration,
+    upgrading: Arc<tokio::sync::Mutex<()>>,
+    tls: Option<TlsAcceptor>,
+    health: Option<ServiceStatuses>,
+    health_checks: Arc<tokio::sync::Mutex<HealthChecks>>,
+    reload_hooks: Arc<Vec<ReloadHook>>,
+}
+
+/// The [`Checker`]s that are running, by check number, and the number to give the next one. Numbers are never reused,
+/// so that a replaced check can't be mistaken for its replacement.
+#[derive(Default)]
+struct HealthChecks {
+    running: Vec<(usize, tokio::task::JoinHandle<()>)>,
+    next_id: usize,
+}
+
+/// What the next shutdown will use. Changing them does not affect a shutdown that has already begun.
+#[derive(Clone, Copy)]
+struct Periods {
+    lame_duck: Duration,
+    grace_period: Option<Duration>,
+}
+
+impl ServerHandle {
+    /// Where the server accepts connections.
+    pub fn local_addrs(&self) -> &[ListenAddr] {
+        &self.local_addrs
+    }
+
+    /// Where the admin service is listening, if it has a listener of its own.
+    pub fn admin_addr(&self) -> Option<&ListenAddr> {
+        self.admin_addr.as_ref()
+    }
+
+    /// Where metrics are served, if anywhere.
+    pub fn metrics_addr(&self) -> Option<&ListenAddr> {
+        self.metrics_addr.as_ref()
+    }
+
+    /// Where the probe endpoints are served, if anywhere.
+    pub fn probes_addr(&self) -> Option<&ListenAddr> {
+        self.probes_addr.as_ref()
+    }
+
+    /// The health status of every service, if the server was given a [`GracefulServer::health_reporter`].
+    pub fn health(&self) -> Option<&ServiceStatuses> {
+        self.health.as_ref()
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// The lame-duck period [`ServerHandle::shutdown`] uses.
+    pub fn lame_duck(&self) -> Duration {
+        self.periods.read().unwrap().lame_duck
+    }
+
+    /// The grace period [`ServerHandle::shutdown`] uses.
+    pub fn grace_period(&self) -> Option<Duration> {
+        self.periods.read().unwrap().grace_period
+    }
+
+    /// Change the lame-duck period for shutdowns that begin from now on. One that is already under way keeps the
+    /// deadlines it started with.
+    pub fn set_lame_duck(&self, lame_duck: Duration) {
+        self.periods.write().unwrap().lame_duck = lame_duck;
+    }
+
+    /// Change the grace period for shutdowns that begin from now on. One that is already under way keeps the
+    /// deadlines it started with, so that a reload can never cut off streams that were promised more time.
+    pub fn set_grace_period(&self, grace_period: Option<Duration>) {
+        self.periods.write().unwrap().grace_period = grace_period;
+    }
+
+    /// The connection limit of the main listeners, see [`GracefulServer::max_connections`].
+    pub fn max_connections(&self) -> Option<usize> {
+        *self.max_connections.read().unwrap()
+    }
+
+    /// Change the connection limit of the main listeners. Lowering it below the number of open connections closes
+    /// none of them; new ones are turned away until enough have closed.
+    pub fn set_max_connections(&self, max_connections: Option<usize>) {
+        *self.max_connections.write().unwrap() = max_connections;
+    }
+
+    /// Stop the running health checks and start `checkers` instead. Services that only the old checks held
+    /// `NOT_SERVING` go back to what the new checks (whose first result counts right away) say. Fails, leaving the
+    /// old checks running, if a checker gates a service that is not served, or there is no health reporter.
+    pub async fn set_health_checks(&self, checkers: Vec<Checker>) -> anyhow::Result<()> {
+        let health = self
+            .health
+            .as_ref()
+            .context("health checks need a health reporter")?;
+        for checker in &checkers {
+            health.ensure_known(checker.gated_services()).await?;
+        }
+        let mut checks = self.health_checks.lock().await;
+        for (id, task) in std::mem::take(&mut checks.running) {
+            task.abort();
+            // Wait for it to stop, so that it can't report on its check after we have forgotten it.
+            let _ = task.await;
+            health.remove_check(id).await;
+        }
+        for checker in checkers {
+            let id = checks.next_id;
+            checks.next_id += 1;
+            let task = tokio::spawn(checker.run(id, health.clone(), self.lifecycle.subscribe()));
+            checks.running.push((id, task));
+        }
+        Ok(())
+    }
+
+    pub fn state(&self) -> Phase {
+        self.lifecycle.phase()
+    }
+
+    pub fn lifecycle(&self) -> &Lifecycle {
+        &self.lifecycle
+    }
+
+    /// The live connections and in-flight streams.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Start shutting down with the configured lame-duck and grace periods. Returns `false` if we were already
+    /// shutting down.
+    pub fn shutdown(&self, reason: ShutdownReason) -> bool {
+        let Periods {
+            lame_duck,
+            grace_period,
+        } = *self.periods.read().unwrap();
+        self.lifecycle
+            .begin_shutdown(reason, lame_duck, grace_period)
+    }
+
+    /// Hand our listeners to a new copy of this binary, and start draining once it is ready to serve. Returns the new
+    /// process's pid. See [`crate::upgrade`].
+    ///
+    /// The drain skips the lame-duck period: the new process is already accepting connections on the same sockets.
+    /// If the new process fails to start, we keep serving as if nothing happened.
+    pub async fn upgrade(&self) -> anyhow::Result<u32> {
+        let Ok(_upgrading) = self.upgrading.try_lock() else {
+            anyhow::bail!("an upgrade is already in progress");
+        };
+        let phase = self.state();
+        if phase != Phase::Serving {
+            anyhow::bail!("can only upgrade while serving, but the server is {phase}");
+        }
+        let pid = upgrade::spawn(&self.handover, self.upgrade_timeout).await?;
+        self.lifecycle.begin_shutdown(
+            ShutdownReason::Upgrade(pid),
+            Duration::ZERO,
+            self.grace_period(),
+        );
+        Ok(pid)
+    }
+
+    /// Re-read the TLS certificates. Connections that are already open keep using the old ones. Returns `false` if the
+    /// server does not use TLS.
+    pub fn reload_tls(&self) -> anyhow::Result<bool> {
+        match &self.tls {
+            Some(tls) => tls.reload().map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Re-read the TLS certificates, then run the [`GracefulServer::on_reload`] hooks. Nothing here touches open
+    /// connections or streams. Failures are logged, and leave the old settings in place.
+    pub fn reload(&self) {
+        match self.reload_tls() {
+            Ok(true) => info!("reloaded TLS certificates"),
+            Ok(false) => {}
+            Err(err) => error!("failed to reload TLS certificates, keeping the old ones: {err:#}"),
+        }
+        for hook in self.reload_hooks.iter() {
+            hook(self);
+        }
+    }
+
+    /// Wait for the warmups to finish (see [`GracefulServer::warmup`]). Returns whether the server is serving, rather
+    /// than shutting down without ever having served.
+    pub async fn ready(&self) -> bool {
+        let mut phase = self.lifecycle.subscribe();
+        phase
+            .wait_for(|phase| *phase != Phase::Starting)
+            .await
+            .is_ok_and(|phase| *phase == Phase::Serving)
+    }
+
+    /// Wait for the server to stop, and return the final phase.
+    ///
+    /// If streams were still open when the grace period ran out, they will have been ended with an `UNAVAILABLE`
+    /// status. Connections that did not close within the force-close timeout after that stay open until the runtime
+    /// shuts down, which for most binaries means returning from `main`.
+    ///
+    /// With [`GracefulServer::metrics`], this also waits for the final scrape, and with a dedicated admin listener (see
+    /// [`GracefulServer::admin_service`]), for that to close.
+    pub async fn wait(&self) -> Phase {
+        let mut phase = self.lifecycle.subscribe();
+        let stopped = phase
+            .wait_for(|phase| matches!(phase, Phase::Stopped { .. }))
+            .await
+            .map(|phase| phase.clone());
+        self.metrics_done.cancelled().await;
+        self.admin_done.cancelled().await;
+        // The lifecycle lives as long as `self`, so the channel can't close out from under us.
+        stopped.unwrap_or_else(|_| self.lifecycle.phase())
+    }
+}
+
+/// Race the server against the grace period, and record how it ended.
+async fn supervise(
+    mut organic: tokio::task::JoinHandle<Result<(), tonic::transport::Error>>,
+    registry: Registry,
+    lifecycle: Lifecycle,
+    force_close_timeout: Duration,
+) {
+    // This future will resolve once we are shutting down and either the deadline has passed or something (e.g. a
+    // second signal) moved us straight to force closing. Along the way it ends the lame-duck period, if there is one.
+    // When it resolves, we need to shut down ungracefully.
+    let ungraceful = {
+        let lifecycle = lifecycle.clone();
+        let mut phase = lifecycle.subscribe();
+        async move {
+            loop {
+                let (lame_duck_until, deadline) =
+                    match phase.wait_for(Phase::is_shutting_down).await {
+                        Ok(phase) => match *phase {
+                            Phase::LameDuck {
+                                until, deadline, ..
+                            } => (Some(until), deadline),
+                            Phase::Draining { deadline, .. } => (None, deadline),
+                            _ => return,
+                        },
+                        Err(_) => return,
+                    };
+                let lame_duck = async {
+                    if let Some(until) = lame_duck_until {
+                        tokio::time::sleep_until(until).await;
+                        lifecycle.drain();
+                    }
+                    std::future::pending().await
+                };
+                let deadline = async {
+                    match deadline {
+                        Some(deadline) => tokio::time::sleep_until(deadline).await,
+                        None => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    () = lame_duck => {},
+                    () = deadline => break,
+                    r = phase.wait_for(|phase| phase.is_force_closing() || !phase.is_shutting_down()) => {
+                        // Unless the shutdown was aborted, we are force closing.
+                        if r.map_or(true, |phase| phase.is_force_closing()) {
+                            break;
+                        }
+                    },
+                }
+            }
+            lifecycle.force_close();
+        }
+    };
+
+    let record = |r: Result<Result<(), tonic::transport::Error>, tokio::task::JoinError>| match r {
+        Ok(Ok(())) => lifecycle.stopped(),
+        // if we hit any kind of organic error with the server, record it so the caller can bubble it up
+        Ok(Err(err)) => lifecycle.failed(ShutdownReason::Fatal(format!("{err:#}"))),
+        Err(err) if err.is_panic() => {
+            let panic = err.into_panic();
+            let msg = panic
+                .downcast_ref::<&str>()
+                .map(|msg| msg.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic payload".to_owned());
+            lifecycle.failed(ShutdownReason::Panic(msg))
+        }
+        Err(err) => lifecycle.failed(ShutdownReason::Fatal(err.to_string())),
+    };
+
+    tokio::select! {
+        r = &mut organic => {
+            record(r);
+            return;
+        },
+        () = ungraceful => {},
+    };
+
+    // Rather than dropping the stragglers on the floor, end each of them with a proper UNAVAILABLE status so that
+    // clients can tell this apart from a crash. Connections have already been sent a GOAWAY, so once the trailers are
+    // flushed they close by themselves and the server finishes organically.
+    let cancelled = registry.cancel_all();
+    info!(
+        cancelled_streams = cancelled,
+        force_close_timeout_ms = force_close_timeout.as_millis() as u64,
+        "ended streams with UNAVAILABLE, waiting for connections to close"
+    );
+    match tokio::time::timeout(force_close_timeout, organic).await {
+        Ok(r) => record(r),
+        Err(_) => {
+            warn!(
+                remaining_streams = registry.streams().len(),
+                remaining_connections = registry.connections().len(),
+                "{} after cancelling, giving up on them",
+                registry.summary(5)
+            );
+            lifecycle.stopped()
+        }
+    };
+}
+
+/// Log a summary of whatever is still open every `interval` while draining.
+async fn report_progress(
+    registry: Registry,
+    mut phase: tokio::sync::watch::Receiver<Phase>,
+    interval: Duration,
+) {
+    // Not just the first drain: a shutdown can be aborted (see `Lifecycle::abort_shutdown`) and started again later.
+    loop {
+        let deadline = match phase
+            .wait_for(|phase| matches!(phase, Phase::Draining { .. } | Phase::Stopped { .. }))
+            .await
+        {
+            Ok(phase) if matches!(*phase, Phase::Draining { .. }) => phase.deadline(),
+            _ => return,
+        };
+        let mut ticker = tokio::time::interval_at(Instant::now() + interval, interval);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    let grace_remaining_ms = deadline
+                        .map(|deadline| deadline.saturating_duration_since(Instant::now()).as_millis() as u64);
+                    info!(
+                        phase = "draining",
+                        remaining_streams = registry.streams().len(),
+                        remaining_connections = registry.connections().len(),
+                        grace_remaining_ms,
+                        "{}",
+                        registry.summary(5)
+                    );
+                }
+                _ = phase.wait_for(|phase| !matches!(phase, Phase::Draining { .. })) => break,
+            }
+        }
+    }
+}
+
+async fn handle_signals_task(mut signals: Signals, handle: ServerHandle) {
+    let lifecycle = &handle.lifecycle;
+    loop {
+        let sig = signals.recv().await;
+        if sig == Signal::Usr2 {
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                info!("recv {sig}, upgrading");
+                if let Err(err) = handle.upgrade().await {
+                    error!("upgrade failed: {err:#}");
+                }
+            });
+            continue;
+        }
+        if sig == Signal::Hup {
+            info!("recv {sig}, reloading");
+            handle.reload();
+            continue;
+        }
+        match lifecycle.phase() {
+            Phase::Starting | Phase::Serving => {
+                handle.shutdown(ShutdownReason::Signal(sig));
+            }
+            Phase::LameDuck { .. } | Phase::Draining { .. } => {
+                warn!("recv {sig} while draining, skipping the rest of the grace period");
+                lifecycle.force_close();
+            }
+            Phase::ForceClosing { .. } | Phase::Stopped { .. } => {
+                error!("recv {sig} while forcing shutdown, aborting");
+                std::process::exit(ABORT_EXIT_CODE);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt as _;
+    use tonic::transport::Channel;
+    use tonic_health::pb::{health_client::HealthClient, HealthCheckRequest};
+
+    use super::*;
+    use crate::health::{self, WatchClose};
+
+    fn localhost() -> ListenAddr {
+        ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+    }
+
+    async fn connect(address: &ListenAddr) -> Channel {
+        let ListenAddr::Tcp(address) = address else {
+            panic!("expected a TCP address, got {address}");
+        };
+        Channel::from_shared(format!("http://{address}"))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn admin_watch_does_not_block_wait() {
+        let server = GracefulServer::builder();
+        // Without a close delay, `Watch` streams never end by themselves.
+        let (reporter, health_service) =
+            health::health_reporter(server.lifecycle(), None, WatchClose::Ok);
+        let handle = server
+            .health_reporter(reporter)
+            .add_admin_service(health_service)
+            .admin_service(Some(SocketAddr::from(([127, 0, 0, 1], 0))))
+            .force_close_timeout(Duration::from_millis(100))
+            .serve(&[localhost()])
+            .await
+            .unwrap();
+        let mut client = HealthClient::new(connect(handle.admin_addr().unwrap()).await);
+        let mut watch = client
+            .watch(HealthCheckRequest {
+                service: String::new(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        watch.message().await.unwrap();
+
+        handle.shutdown(ShutdownReason::Admin("test".to_owned()));
+        tokio::time::timeout(Duration::from_secs(5), handle.wait())
+            .await
+            .expect("an open admin Watch kept the server from stopping");
+        let status = loop {
+            match watch.message().await {
+                Ok(Some(_)) => {}
+                Ok(None) => panic!("the Watch ended without a status"),
+                Err(status) => break status,
+            }
+        };
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+    }
+
+    #[tokio::test]
+    async fn failed_start_does_not_wait_for_streams() {
+        let server = GracefulServer::builder();
+        let (reporter, health_service) =
+            health::health_reporter(server.lifecycle(), None, WatchClose::Ok);
+        let handle = server
+            .health_reporter(reporter)
+            .add_service(health_service)
+            .warmup(std::future::pending())
+            .startup_timeout(Some(Duration::from_millis(200)))
+            .force_close_timeout(Duration::from_millis(100))
+            .serve(&[localhost()])
+            .await
+            .unwrap();
+        let mut client = HealthClient::new(connect(&handle.local_addrs()[0]).await);
+        let mut watch = client
+            .watch(HealthCheckRequest {
+                service: String::new(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        watch.message().await.unwrap();
+
+        let phase = tokio::time::timeout(Duration::from_secs(5), handle.wait())
+            .await
+            .expect("a stream opened during startup kept the server from stopping");
+        assert!(matches!(
+            phase,
+            Phase::Stopped {
+                reason: ShutdownReason::Startup(_),
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn closes_connections_over_the_limit() {
+        let server = GracefulServer::builder();
+        let (reporter, health_service) =
+            health::health_reporter(server.lifecycle(), None, WatchClose::Ok);
+        let handle = server
+            .health_reporter(reporter)
+            .add_service(health_service)
+            .max_connections(Some(1))
+            .serve(&[localhost()])
+            .await
+            .unwrap();
+        let address = &handle.local_addrs()[0];
+        let check = |channel| async move {
+            HealthClient::new(channel)
+                .check(HealthCheckRequest {
+                    service: String::new(),
+                })
+                .await
+        };
+        check(connect(address).await).await.unwrap();
+
+        let ListenAddr::Tcp(tcp) = address else {
+            unreachable!()
+        };
+        let mut over = tokio::net::TcpStream::connect(tcp).await.unwrap();
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), over.read(&mut buf))
+            .await
+            .expect("the connection over the limit was left open");
+        assert!(matches!(read, Ok(0) | Err(_)), "{read:?}");
+
+        handle.set_max_connections(Some(2));
+        check(connect(address).await).await.unwrap();
+    }
+}
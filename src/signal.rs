@@ -0,0 +1,62 @@
+use std::fmt;
+
+use tokio::signal::unix::{signal, SignalKind};
+
+/// The process signals we act on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Signal {
+    Term,
+    Int,
+    Quit,
+    /// Asks for a zero-downtime upgrade rather than a shutdown, see [`crate::upgrade`].
+    Usr2,
+    /// Asks for certificates and settings to be reloaded, see [`crate::ServerHandle::reload`].
+    Hup,
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Signal::Term => "SIGTERM",
+            Signal::Int => "SIGINT",
+            Signal::Quit => "SIGQUIT",
+            Signal::Usr2 => "SIGUSR2",
+            Signal::Hup => "SIGHUP",
+        })
+    }
+}
+
+/// Listens for SIGTERM, SIGINT, SIGQUIT, SIGUSR2 and SIGHUP at once.
+///
+/// The handlers are installed when this is constructed, so build it before the server starts listening: a signal
+/// that arrives before the handlers exist would otherwise take the default action and kill the process.
+pub struct Signals {
+    term: tokio::signal::unix::Signal,
+    int: tokio::signal::unix::Signal,
+    quit: tokio::signal::unix::Signal,
+    usr2: tokio::signal::unix::Signal,
+    hup: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            term: signal(SignalKind::terminate())?,
+            int: signal(SignalKind::interrupt())?,
+            quit: signal(SignalKind::quit())?,
+            usr2: signal(SignalKind::user_defined2())?,
+            hup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    /// Wait for the next signal.
+    pub async fn recv(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.term.recv() => Signal::Term,
+            _ = self.int.recv() => Signal::Int,
+            _ = self.quit.recv() => Signal::Quit,
+            _ = self.usr2.recv() => Signal::Usr2,
+            _ = self.hup.recv() => Signal::Hup,
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use std::{
+    env, io,
+    os::unix::net::{SocketAddr as UnixAddr, UnixDatagram},
+    path::Path,
+    time::Duration,
+};
+
+use tokio::{
+    sync::watch,
+    time::{Instant, MissedTickBehavior},
+};
+use tracing::warn;
+
+use crate::{
+    lifecycle::{Phase, ShutdownReason},
+    registry::Registry,
+};
+
+/// How often to refresh `STATUS=` while shutting down.
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sends `sd_notify` messages to the service manager, for services that run with `Type=notify`.
+///
+/// This speaks the datagram protocol directly, so it works against any `AF_UNIX` datagram socket, not just systemd's:
+/// point `NOTIFY_SOCKET` (or [`Notifier::new`]) at a socket of your own to see what would be sent.
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: UnixAddr,
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// The notifier that systemd asked for through `NOTIFY_SOCKET`, if any, with the watchdog from `WATCHDOG_USEC`.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+            return Ok(None);
+        };
+        let notifier = Self::new(path)?.watchdog(watchdog_from_env());
+        Ok(Some(notifier))
+    }
+
+    /// Notify the datagram socket at `path`. A leading `@` means an abstract socket, as in `NOTIFY_SOCKET`.
+    pub fn new(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let addr = match path.to_str().and_then(|path| path.strip_prefix('@')) {
+            Some(name) => abstract_addr(name)?,
+            None => UnixAddr::from_pathname(path)?,
+        };
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            addr,
+            watchdog: None,
+        })
+    }
+
+    /// Send `WATCHDOG=1` twice per `interval`, for as long as the server runs.
+    pub fn watchdog(mut self, interval: Option<Duration>) -> Self {
+        self.watchdog = interval;
+        self
+    }
+
+    /// Send newline-separated `KEY=value` assignments, e.g. `"READY=1\nSTATUS=serving"`.
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        self.socket.send_to_addr(state.as_bytes(), &self.addr)?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn abstract_addr(name: &str) -> io::Result<UnixAddr> {
+    use std::os::linux::net::SocketAddrExt;
+    UnixAddr::from_abstract_name(name)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_addr(_name: &str) -> io::Result<UnixAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract sockets are only supported on Linux",
+    ))
+}
+
+/// `WATCHDOG_USEC`, unless `WATCHDOG_PID` says it is meant for another process.
+fn watchdog_from_env() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec)).filter(|interval| !interval.is_zero())
+}
+
+/// Keep the service manager up to date with the lifecycle until the server stops.
+///
+/// `READY=1` goes out once we are serving, and `STOPPING=1` once the drain starts. Until then, a drain can still be
+/// aborted, so the lame-duck period is only reported through `STATUS=`. While shutting down, `EXTEND_TIMEOUT_USEC`
+/// keeps systemd's stop timeout in line with our own deadline (a grace period of forever leaves it alone).
+///
+/// After an upgrade, none of that applies: the service is not stopping, it just has a new main process. We say so
+/// with `MAINPID=` (which needs `NotifyAccess=all`) and then keep quiet, so as not to overwrite the new process's
+/// status.
+pub(crate) async fn notify_lifecycle(
+    notifier: Notifier,
+    registry: Registry,
+    mut phase: watch::Receiver<Phase>,
+    force_close_timeout: Duration,
+) {
+    let notify = |state: String| {
+        if let Err(err) = notifier.notify(&state) {
+            warn!("failed to notify service manager: {err}");
+        }
+    };
+    let mut watchdog = notifier.watchdog.map(|interval| {
+        let period = interval / 2;
+        let mut watchdog = tokio::time::interval_at(Instant::now() + period, period);
+        watchdog.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        watchdog
+    });
+    let mut refresh = tokio::time::interval(STATUS_INTERVAL);
+    loop {
+        let current = phase.borrow_and_update().clone();
+        let line = status(&current, &registry);
+        let handed_over = match current.reason() {
+            Some(ShutdownReason::Upgrade(pid)) => Some(*pid),
+            _ => None,
+        };
+        match (&current, handed_over) {
+            (Phase::Stopped { .. }, Some(pid)) => {
+                notify(format!("MAINPID={pid}"));
+                return;
+            }
+            (_, Some(pid)) => notify(format!("MAINPID={pid}")),
+            (Phase::Starting, None) => {}
+            (Phase::Serving, None) => notify(format!("READY=1\nSTATUS={line}")),
+            (Phase::LameDuck { deadline, .. }, None) => notify(format!(
+                "STATUS={line}{}",
+                extend_timeout(*deadline, force_close_timeout)
+            )),
+            (Phase::Draining { deadline, .. }, None) => notify(format!(
+                "STOPPING=1\nSTATUS={line}{}",
+                extend_timeout(*deadline, force_close_timeout)
+            )),
+            (Phase::ForceClosing { .. }, None) => notify(format!(
+                "STOPPING=1\nSTATUS={line}\nEXTEND_TIMEOUT_USEC={}",
+                force_close_timeout.as_micros()
+            )),
+            (Phase::Stopped { .. }, None) => {
+                notify(format!("STOPPING=1\nSTATUS={line}"));
+                return;
+            }
+        }
+        refresh.reset();
+
+        // Wait for the next transition, refreshing the status and petting the watchdog in the meantime.
+        loop {
+            let ping = async {
+                match &mut watchdog {
+                    Some(watchdog) => watchdog.tick().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                changed = phase.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    break;
+                }
+                _ = ping => notify("WATCHDOG=1".to_owned()),
+                _ = refresh.tick(), if current.is_shutting_down() && handed_over.is_none() => {
+                    notify(format!("STATUS={}", status(&current, &registry)));
+                }
+            }
+        }
+    }
+}
+
+/// What `STATUS=` says in each phase.
+fn status(phase: &Phase, registry: &Registry) -> String {
+    let streams = registry.streams().len();
+    match phase {
+        Phase::Starting => "starting".to_owned(),
+        Phase::Serving => "serving".to_owned(),
+        Phase::LameDuck { .. } => format!("lame duck, {streams} streams open"),
+        Phase::Draining { .. } => format!("draining, {streams} streams left"),
+        Phase::ForceClosing { .. } => format!("force closing, {streams} streams left"),
+        Phase::Stopped { reason, .. } => format!("stopped: {reason}"),
+    }
+}
+
+/// An `EXTEND_TIMEOUT_USEC` line that gives us until `deadline` plus the time it takes to force-close.
+fn extend_timeout(deadline: Option<Instant>, force_close_timeout: Duration) -> String {
+    match deadline {
+        Some(deadline) => format!(
+            "\nEXTEND_TIMEOUT_USEC={}",
+            (deadline.saturating_duration_since(Instant::now()) + force_close_timeout).as_micros()
+        ),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UnixDatagram;
+
+    use super::*;
+    use crate::lifecycle::Lifecycle;
+
+    /// A datagram socket standing in for systemd's, and a notifier pointed at it.
+    fn socket(name: &str) -> (UnixDatagram, Notifier, impl Drop) {
+        struct RemoveOnDrop(std::path::PathBuf);
+        impl Drop for RemoveOnDrop {
+            fn drop(&mut self) {
+                let _ = std::fs::remove_file(&self.0);
+            }
+        }
+        let path = env::temp_dir().join(format!("notify-{name}-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        let notifier = Notifier::new(&path).unwrap();
+        (socket, notifier, RemoveOnDrop(path))
+    }
+
+    async fn recv(socket: &UnixDatagram) -> String {
+        let mut buf = vec![0; 1024];
+        let len = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+            .await
+            .expect("no datagram within 5s")
+            .unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    /// The `EXTEND_TIMEOUT_USEC=` value in `state`, which must be its last line.
+    fn extend_timeout_usec(state: &str) -> u128 {
+        let (_, usec) = state.rsplit_once("\nEXTEND_TIMEOUT_USEC=").unwrap();
+        usec.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn notify_sends_datagrams() {
+        let (socket, notifier, _guard) = socket("raw");
+        notifier.notify("READY=1\nSTATUS=hello").unwrap();
+        assert_eq!(recv(&socket).await, "READY=1\nSTATUS=hello");
+    }
+
+    #[tokio::test]
+    async fn from_env_reads_notify_socket_and_watchdog() {
+        let (socket, _, _guard) = socket("env");
+        let path = socket.local_addr().unwrap();
+        // No other test reads these variables, so setting them here can't race.
+        env::set_var("NOTIFY_SOCKET", path.as_pathname().unwrap());
+        env::set_var("WATCHDOG_USEC", "2000000");
+        env::set_var("WATCHDOG_PID", std::process::id().to_string());
+        let notifier = Notifier::from_env().unwrap().unwrap();
+        assert_eq!(notifier.watchdog, Some(Duration::from_secs(2)));
+        notifier.notify("READY=1").unwrap();
+        assert_eq!(recv(&socket).await, "READY=1");
+
+        env::set_var("WATCHDOG_PID", "1");
+        assert_eq!(Notifier::from_env().unwrap().unwrap().watchdog, None);
+        env::remove_var("NOTIFY_SOCKET");
+        assert!(Notifier::from_env().unwrap().is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn notifies_abstract_sockets() {
+        use std::os::linux::net::SocketAddrExt;
+        let name = format!("notify-abstract-{}", std::process::id());
+        let socket = std::os::unix::net::UnixDatagram::bind_addr(
+            &UnixAddr::from_abstract_name(&name).unwrap(),
+        )
+        .unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let socket = UnixDatagram::from_std(socket).unwrap();
+        Notifier::new(format!("@{name}"))
+            .unwrap()
+            .notify("READY=1")
+            .unwrap();
+        assert_eq!(recv(&socket).await, "READY=1");
+    }
+
+    #[tokio::test]
+    async fn notifies_every_phase() {
+        let (socket, notifier, _guard) = socket("phases");
+        let lifecycle = Lifecycle::new();
+        let force_close_timeout = Duration::from_secs(1);
+        let task = tokio::spawn(notify_lifecycle(
+            notifier,
+            Registry::new(),
+            lifecycle.subscribe(),
+            force_close_timeout,
+        ));
+
+        assert!(lifecycle.serving());
+        assert_eq!(recv(&socket).await, "READY=1\nSTATUS=serving");
+
+        let reason = ShutdownReason::Admin("test".to_owned());
+        let grace_period = Duration::from_secs(10);
+        assert!(lifecycle.begin_shutdown(reason, Duration::from_secs(5), Some(grace_period)));
+        let state = recv(&socket).await;
+        assert!(
+            state.starts_with("STATUS=lame duck, 0 streams open\n"),
+            "{state}"
+        );
+        let usec = extend_timeout_usec(&state);
+        assert!(usec <= (grace_period + force_close_timeout).as_micros());
+        assert!(usec > grace_period.as_micros());
+
+        assert!(lifecycle.drain());
+        let state = recv(&socket).await;
+        assert!(
+            state.starts_with("STOPPING=1\nSTATUS=draining, 0 streams left\n"),
+            "{state}"
+        );
+        assert!(extend_timeout_usec(&state) <= (grace_period + force_close_timeout).as_micros());
+
+        assert!(lifecycle.force_close());
+        assert_eq!(
+            recv(&socket).await,
+            "STOPPING=1\nSTATUS=force closing, 0 streams left\nEXTEND_TIMEOUT_USEC=1000000"
+        );
+
+        assert!(lifecycle.stopped());
+        assert_eq!(
+            recv(&socket).await,
+            "STOPPING=1\nSTATUS=stopped: admin request: test"
+        );
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn aborted_lame_duck_is_ready_again_without_stopping() {
+        let (socket, notifier, _guard) = socket("abort");
+        let lifecycle = Lifecycle::new();
+        tokio::spawn(notify_lifecycle(
+            notifier,
+            Registry::new(),
+            lifecycle.subscribe(),
+            Duration::from_secs(1),
+        ));
+        lifecycle.serving();
+        assert_eq!(recv(&socket).await, "READY=1\nSTATUS=serving");
+        // Without a grace period there is no deadline to extend the stop timeout to.
+        lifecycle.begin_shutdown(
+            ShutdownReason::Admin("test".to_owned()),
+            Duration::from_secs(5),
+            None,
+        );
+        assert_eq!(recv(&socket).await, "STATUS=lame duck, 0 streams open");
+        lifecycle.abort_shutdown();
+        assert_eq!(recv(&socket).await, "READY=1\nSTATUS=serving");
+    }
+
+    #[tokio::test]
+    async fn pets_the_watchdog_twice_per_interval() {
+        let (socket, notifier, _guard) = socket("watchdog");
+        let lifecycle = Lifecycle::new();
+        let interval = Duration::from_millis(100);
+        tokio::spawn(notify_lifecycle(
+            notifier.watchdog(Some(interval)),
+            Registry::new(),
+            lifecycle.subscribe(),
+            Duration::from_secs(1),
+        ));
+        let started = std::time::Instant::now();
+        assert_eq!(recv(&socket).await, "WATCHDOG=1");
+        assert_eq!(recv(&socket).await, "WATCHDOG=1");
+        assert!(started.elapsed() >= interval);
+    }
+
+    #[tokio::test]
+    async fn upgrade_hands_over_main_pid() {
+        let (socket, notifier, _guard) = socket("upgrade");
+        let lifecycle = Lifecycle::new();
+        let task = tokio::spawn(notify_lifecycle(
+            notifier,
+            Registry::new(),
+            lifecycle.subscribe(),
+            Duration::from_secs(1),
+        ));
+        lifecycle.serving();
+        assert_eq!(recv(&socket).await, "READY=1\nSTATUS=serving");
+        lifecycle.begin_shutdown(ShutdownReason::Upgrade(42), Duration::ZERO, None);
+        assert_eq!(recv(&socket).await, "MAINPID=42");
+        lifecycle.stopped();
+        assert_eq!(recv(&socket).await, "MAINPID=42");
+        task.await.unwrap();
+    }
+}
@@ -0,0 +1,301 @@
+//! TLS, with certificates that can be swapped while the server runs.
+//!
+//! tonic's `ServerTlsConfig` is fixed once the server starts, so we do the handshakes ourselves, in front of tonic:
+//! every handshake uses whatever certificate is current when the connection arrives. Reloading only affects new
+//! connections; established ones keep the session they negotiated.
+
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context as _;
+use tokio::{sync::watch, task::JoinSet};
+use tokio_rustls::rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use tokio_stream::Stream;
+use tracing::{debug, info, warn};
+
+use crate::{lifecycle::Phase, listener::Conn};
+
+/// How long a client gets to finish the handshake before we hang up.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often to check the certificate files for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Where to find the server's certificate and key, and optionally the CA that client certificates must be signed by.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    cert: PathBuf,
+    key: PathBuf,
+    client_ca: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    /// Serve the PEM certificate chain in `cert`, with the PEM private key in `key`.
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        Self {
+            cert: cert.into(),
+            key: key.into(),
+            client_ca: None,
+        }
+    }
+
+    /// Require clients to present a certificate signed by one of the PEM certificates in `client_ca` (mutual TLS).
+    pub fn client_ca(mut self, client_ca: Option<PathBuf>) -> Self {
+        self.client_ca = client_ca;
+        self
+    }
+
+    fn paths(&self) -> impl Iterator<Item = &Path> {
+        [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(PathBuf::as_path)
+    }
+
+    fn load(&self) -> anyhow::Result<ServerConfig> {
+        let provider = Arc::new(ring::default_provider());
+        let certs = read_certs(&self.cert)?;
+        let key = read_key(&self.key)?;
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(client_ca)? {
+                    roots.add(cert).with_context(|| {
+                        format!("invalid CA certificate in {}", client_ca.display())
+                    })?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                    .build()
+                    .context("invalid client CA")?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_single_cert(certs, key).with_context(|| {
+            format!(
+                "{} does not go with {}",
+                self.key.display(),
+                self.cert.display()
+            )
+        })?;
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        Ok(config)
+    }
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<io::Result<Vec<_>>>()
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    anyhow::ensure!(!certs.is_empty(), "no certificates in {}", path.display());
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("failed to read {}", path.display()))?
+        .with_context(|| format!("no private key in {}", path.display()))
+}
+
+/// Terminates TLS on accepted connections, with the most recently loaded certificates.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    config: Arc<TlsConfig>,
+    current: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl TlsAcceptor {
+    /// Load the certificates in `config`, failing if any of them are missing or invalid.
+    pub fn new(config: TlsConfig) -> anyhow::Result<Self> {
+        let current = config.load()?;
+        Ok(Self {
+            config: Arc::new(config),
+            current: Arc::new(RwLock::new(Arc::new(current))),
+        })
+    }
+
+    /// Re-read the certificate files. If they are invalid, the ones loaded before stay in use.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let config = self.config.load()?;
+        *self.current.write().unwrap() = Arc::new(config);
+        Ok(())
+    }
+
+    /// Wrap a stream of accepted connections into one of connections that completed the TLS handshake.
+    ///
+    /// Handshakes run concurrently, so a slow client cannot hold up the ones behind it. A failed handshake only affects
+    /// its own connection, not the listener, so it is logged and dropped.
+    pub(crate) fn accept<S>(&self, incoming: S) -> TlsIncoming<S> {
+        TlsIncoming {
+            incoming: Some(incoming),
+            acceptor: self.clone(),
+            handshakes: JoinSet::new(),
+        }
+    }
+
+    /// The modification time of each certificate file, to tell when they change.
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.config
+            .paths()
+            .map(|path| path.metadata().and_then(|meta| meta.modified()).ok())
+            .collect()
+    }
+}
+
+/// Reload the certificates whenever their files change on disk, until the server stops.
+///
+/// A rotation that writes the certificate and key one after the other can be caught halfway; the mismatch is logged,
+/// and the reload is retried when the second file lands.
+pub(crate) async fn reload_on_change(acceptor: TlsAcceptor, mut phase: watch::Receiver<Phase>) {
+    let mut modified = acceptor.modified();
+    let mut ticker = tokio::time::interval(WATCH_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = phase.wait_for(|phase| matches!(phase, Phase::Stopped { .. })) => return,
+        }
+        let now = acceptor.modified();
+        if now == modified {
+            continue;
+        }
+        modified = now;
+        match acceptor.reload() {
+            Ok(()) => info!("certificate files changed, reloaded them"),
+            Err(err) => warn!("certificate files changed, but failed to reload them: {err:#}"),
+        }
+    }
+}
+
+/// The stream returned by [`TlsAcceptor::accept`].
+pub(crate) struct TlsIncoming<S> {
+    /// `None` once the underlying stream has ended.
+    incoming: Option<S>,
+    acceptor: TlsAcceptor,
+    handshakes: JoinSet<io::Result<Conn>>,
+}
+
+impl<S> TlsIncoming<S> {
+    fn start_handshake(&mut self, conn: Conn) {
+        let acceptor =
+            tokio_rustls::TlsAcceptor::from(self.acceptor.current.read().unwrap().clone());
+        self.handshakes.spawn(async move {
+            let peer = conn.peer_addr();
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(conn)).await {
+                Ok(Ok(stream)) => Ok(Conn::Tls(Box::new(stream))),
+                Ok(Err(err)) => Err(err),
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no handshake within {}ms", HANDSHAKE_TIMEOUT.as_millis()),
+                )),
+            }
+            .inspect_err(|err| match peer {
+                Some(peer) => debug!("TLS handshake with {peer} failed: {err}"),
+                None => debug!("TLS handshake failed: {err}"),
+            })
+        });
+    }
+}
+
+impl<S> Stream for TlsIncoming<S>
+where
+    S: Stream<Item = Conn> + Unpin,
+{
+    type Item = Conn;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Start a handshake for everything that is waiting to be accepted.
+        while let Some(incoming) = &mut self.incoming {
+            match Pin::new(incoming).poll_next(cx) {
+                Poll::Ready(Some(conn)) => self.start_handshake(conn),
+                Poll::Ready(None) => self.incoming = None,
+                Poll::Pending => break,
+            }
+        }
+        loop {
+            match self.handshakes.poll_join_next(cx) {
+                Poll::Ready(Some(Ok(Ok(conn)))) => return Poll::Ready(Some(conn)),
+                // Already logged.
+                Poll::Ready(Some(Ok(Err(_)))) => continue,
+                Poll::Ready(Some(Err(err))) => {
+                    warn!("TLS handshake task failed: {err}");
+                    continue;
+                }
+                Poll::Ready(None) if self.incoming.is_none() => return Poll::Ready(None),
+                // The underlying stream has registered for a wakeup.
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    /// Two self-signed certificates for `localhost`, each with its own key.
+    const CERTS: [(&str, &str); 2] = [
+        (
+            include_str!("testdata/a.crt"),
+            include_str!("testdata/a.key"),
+        ),
+        (
+            include_str!("testdata/b.crt"),
+            include_str!("testdata/b.key"),
+        ),
+    ];
+
+    fn current(acceptor: &TlsAcceptor) -> Arc<ServerConfig> {
+        acceptor.current.read().unwrap().clone()
+    }
+
+    #[test]
+    fn reload_keeps_the_old_certificate_on_errors() {
+        let dir = env::temp_dir().join(format!("tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("server.crt"), dir.join("server.key"));
+        let write = |(pem_cert, pem_key): (&str, &str)| {
+            fs::write(&cert, pem_cert).unwrap();
+            fs::write(&key, pem_key).unwrap();
+        };
+
+        assert!(TlsAcceptor::new(TlsConfig::new(&cert, &key)).is_err());
+        write(CERTS[0]);
+        let acceptor = TlsAcceptor::new(TlsConfig::new(&cert, &key)).unwrap();
+        let loaded = current(&acceptor);
+        assert_eq!(loaded.alpn_protocols, [b"h2"]);
+
+        // Caught halfway through a rotation.
+        write((CERTS[1].0, CERTS[0].1));
+        let err = acceptor.reload().unwrap_err();
+        assert!(format!("{err:#}").contains("does not go with"), "{err:#}");
+        assert!(Arc::ptr_eq(&current(&acceptor), &loaded));
+
+        write((CERTS[1].0, ""));
+        let err = acceptor.reload().unwrap_err();
+        assert!(format!("{err:#}").contains("no private key"), "{err:#}");
+        assert!(Arc::ptr_eq(&current(&acceptor), &loaded));
+
+        write(CERTS[1]);
+        acceptor.reload().unwrap();
+        assert!(!Arc::ptr_eq(&current(&acceptor), &loaded));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
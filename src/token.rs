@@ -0,0 +1,105 @@
+use std::task::{Context, Poll};
+
+use tokio::{sync::watch, time::Instant};
+use tower::{Layer, Service};
+
+use crate::lifecycle::{Lifecycle, Phase};
+
+/// Tells request handlers that the server has started draining, so that long-running streams can wrap up on their
+/// own terms instead of waiting to be cut off.
+///
+/// Every request served by a [`crate::GracefulServer`] carries one in its extensions:
+/// ```
+/// # use tonic_shutdown_example::ShutdownToken;
+/// # async fn next_message() {}
+/// # async fn handle(request: tonic::Request<()>) {
+/// let token = ShutdownToken::from_request(&request).expect("served by GracefulServer");
+/// tokio::select! {
+///     _ = token.cancelled() => { /* send a final message and end the stream */ }
+///     msg = next_message() => { /* ... */ }
+/// }
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ShutdownToken {
+    phase: watch::Receiver<Phase>,
+}
+
+impl ShutdownToken {
+    pub fn new(lifecycle: &Lifecycle) -> Self {
+        Self {
+            phase: lifecycle.subscribe(),
+        }
+    }
+
+    /// The token that [`ShutdownTokenLayer`] attached to `request`, if any.
+    pub fn from_request<T>(request: &tonic::Request<T>) -> Option<Self> {
+        request.extensions().get::<Self>().cloned()
+    }
+
+    /// Whether the drain has started, i.e. the listener is closed and clients have been asked to go away.
+    pub fn is_cancelled(&self) -> bool {
+        self.phase.borrow().is_draining()
+    }
+
+    /// Resolves once the drain has started.
+    pub async fn cancelled(&self) {
+        let mut phase = self.phase.clone();
+        let _ = phase.wait_for(Phase::is_draining).await;
+    }
+
+    /// When streams that are still open will be cut off. `None` if we are not shutting down, or if the server will
+    /// wait for them forever.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.phase.borrow().deadline()
+    }
+}
+
+/// Attaches a [`ShutdownToken`] to every request.
+#[derive(Clone)]
+pub struct ShutdownTokenLayer {
+    token: ShutdownToken,
+}
+
+impl ShutdownTokenLayer {
+    pub fn new(lifecycle: &Lifecycle) -> Self {
+        Self {
+            token: ShutdownToken::new(lifecycle),
+        }
+    }
+}
+
+impl<S> Layer<S> for ShutdownTokenLayer {
+    type Service = ShutdownTokenService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ShutdownTokenService {
+            inner,
+            token: self.token.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ShutdownTokenService<S> {
+    inner: S,
+    token: ShutdownToken,
+}
+
+impl<S, ReqBody> Service<http::Request<ReqBody>> for ShutdownTokenService<S>
+where
+    S: Service<http::Request<ReqBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<ReqBody>) -> Self::Future {
+        req.extensions_mut().insert(self.token.clone());
+        self.inner.call(req)
+    }
+}
@@ -0,0 +1,256 @@
+//! Zero-downtime upgrades: start a new copy of the binary, hand it our listening sockets, and only start draining once
+//! it says it is ready. The listeners never close, so clients never see a refused connection.
+//!
+//! The old process passes each listener's file descriptor (and the write end of a pipe) down through the exec, and
+//! names them in [`LISTEN_FDS_ENV`] and [`READY_FD_ENV`]. The new process picks them up with
+//! [`Handover::from_env`], serves them, and calls [`Handover::ready`], which writes a byte to the pipe.
+
+use std::{
+    env,
+    fs::File,
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+
+use crate::listener::Listener;
+
+/// The inherited listeners, as comma-separated `fd:name` pairs. A `:remove` suffix means that the new process is
+/// responsible for removing the listener's socket file.
+pub const LISTEN_FDS_ENV: &str = "TONIC_SHUTDOWN_LISTEN_FDS";
+/// The write end of the pipe that tells the old process we are ready.
+pub const READY_FD_ENV: &str = "TONIC_SHUTDOWN_READY_FD";
+
+/// What the old process handed over to us.
+pub struct Handover {
+    /// The listeners, named as [`crate::GracefulServer`] named them: `grpc` for the main listeners, `admin` for the
+    /// admin listener.
+    pub listeners: Vec<(String, Listener)>,
+    ready: Option<File>,
+}
+
+impl Handover {
+    /// Take over the listeners and readiness pipe passed down by the process that started us. `None` if this is not an
+    /// upgrade.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let Ok(fds) = env::var(LISTEN_FDS_ENV) else {
+            return Ok(None);
+        };
+        let listeners = fds
+            .split(',')
+            .filter(|fd| !fd.is_empty())
+            .map(|entry| {
+                let mut fields = entry.split(':');
+                let fd = fields.next().unwrap_or_default();
+                let name = fields.next().unwrap_or("unknown");
+                let remove_on_stop = fields.next() == Some("remove");
+                let fd = fd.parse().map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid {LISTEN_FDS_ENV} entry `{entry}`"),
+                    )
+                })?;
+                // SAFETY: the old process passed this file descriptor down to us, and nothing else claims it.
+                let mut listener = unsafe { Listener::from_raw_fd(fd)? };
+                if remove_on_stop {
+                    listener = listener.remove_on_stop()?;
+                }
+                Ok((name.to_owned(), listener))
+            })
+            .collect::<io::Result<_>>()?;
+        let ready = match env::var(READY_FD_ENV).ok().and_then(|fd| fd.parse().ok()) {
+            Some(fd) => Some(claim_fd(fd)?),
+            None => None,
+        };
+        Ok(Some(Self { listeners, ready }))
+    }
+
+    /// Tell the old process that we are serving, so that it can start draining.
+    pub fn ready(self) -> io::Result<()> {
+        match self.ready {
+            Some(mut pipe) => pipe.write_all(b"1"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Take ownership of `fd`, after checking that it is open, and mark it close-on-exec.
+fn claim_fd(fd: RawFd) -> io::Result<File> {
+    // SAFETY: F_GETFD and F_SETFD only touch the descriptor flags, and fail cleanly if `fd` is not open.
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1
+        || unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` is open, and the old process passed it down to us, so nothing else owns it.
+    Ok(File::from(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
+
+/// Start a new copy of this binary, with the same arguments, that serves `listeners`. Resolves with its pid once it
+/// reports that it is ready. If it does not become ready within `timeout`, we keep serving; if it has not exited by
+/// then either, it is killed.
+///
+/// The binary is looked up through `argv[0]` rather than `/proc/self/exe`, so that a binary that was replaced on disk
+/// is picked up.
+pub(crate) async fn spawn(
+    listeners: &[(RawFd, &str, bool)],
+    timeout: Duration,
+) -> anyhow::Result<u32> {
+    let mut args: Vec<_> = env::args_os().collect();
+    anyhow::ensure!(!args.is_empty(), "no argv[0] to re-exec");
+    let program = args.remove(0);
+    let (ready_read, ready_write) = pipe()?;
+
+    let mut inherit: Vec<RawFd> = listeners.iter().map(|(fd, _, _)| *fd).collect();
+    inherit.push(ready_write.as_raw_fd());
+    let fds = listeners
+        .iter()
+        .map(|(fd, name, remove_on_stop)| {
+            let suffix = if *remove_on_stop { ":remove" } else { "" };
+            format!("{fd}:{name}{suffix}")
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let mut command = tokio::process::Command::new(program);
+    command
+        .args(args)
+        .env(LISTEN_FDS_ENV, fds)
+        .env(READY_FD_ENV, ready_write.as_raw_fd().to_string())
+        // systemd points the watchdog at the main process, which the new process is about to become.
+        .env_remove("WATCHDOG_PID");
+    // SAFETY: fcntl is async-signal-safe, and `inherit` was allocated before the fork.
+    unsafe {
+        command.pre_exec(move || {
+            // Let the file descriptors survive the exec, in the child only.
+            for &fd in &inherit {
+                if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().context("failed to start new process")?;
+    let pid = child.id().context("new process exited immediately")?;
+    // Only the child may hold the write end, so that we see EOF if it dies.
+    drop(ready_write);
+
+    let ready = tokio::task::spawn_blocking(move || {
+        let mut buf = [0; 1];
+        File::from(ready_read).read(&mut buf)
+    });
+    let deadline = tokio::time::Instant::now() + timeout;
+    match tokio::time::timeout_at(deadline, ready).await {
+        Ok(Ok(Ok(1))) => Ok(pid),
+        Ok(Ok(Ok(_))) => {
+            // It gave up on starting, but may still be draining connections it accepted on our listeners, with no
+            // grace period to bound that. We must not hold up later upgrades for it.
+            match tokio::time::timeout_at(deadline, child.wait()).await {
+                Ok(status) => {
+                    anyhow::bail!(
+                        "new process {pid} exited before becoming ready: {}",
+                        status?
+                    )
+                }
+                Err(_) => {
+                    let _ = child.kill().await;
+                    anyhow::bail!(
+                        "new process {pid} gave up before becoming ready, and did not exit within {}ms, killed it",
+                        timeout.as_millis()
+                    )
+                }
+            }
+        }
+        Ok(Ok(Err(err))) => {
+            let _ = child.kill().await;
+            Err(err).context("failed to wait for new process")
+        }
+        Ok(Err(err)) => {
+            let _ = child.kill().await;
+            Err(err.into())
+        }
+        Err(_) => {
+            // Killing the child closes the write end, which also ends the blocking read.
+            let _ = child.kill().await;
+            anyhow::bail!(
+                "new process {pid} did not become ready within {}ms, killed it",
+                timeout.as_millis()
+            )
+        }
+    }
+}
+
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two file descriptors pipe2 returns.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: pipe2 just opened these, and nothing else knows about them.
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::listener::ListenAddr;
+
+    fn dup(fd: RawFd) -> RawFd {
+        // SAFETY: dup only creates a new descriptor for `fd`, which the caller keeps open.
+        let fd = unsafe { libc::dup(fd) };
+        assert_ne!(fd, -1, "{}", io::Error::last_os_error());
+        fd
+    }
+
+    // The environment is shared by every test, so this is the only one that touches it.
+    #[tokio::test]
+    async fn takes_over_listeners_and_reports_ready() {
+        env::remove_var(LISTEN_FDS_ENV);
+        assert!(Handover::from_env().unwrap().is_none());
+
+        let address = ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 0)));
+        let grpc = Listener::bind(&address).await.unwrap();
+        let admin = Listener::bind(&address).await.unwrap();
+        let (ready_read, ready_write) = pipe().unwrap();
+        env::set_var(
+            LISTEN_FDS_ENV,
+            format!(
+                "{}:grpc,{}:admin",
+                dup(grpc.as_raw_fd()),
+                dup(admin.as_raw_fd())
+            ),
+        );
+        // The handover takes ownership of the write end.
+        let ready_write = std::mem::ManuallyDrop::new(ready_write);
+        env::set_var(READY_FD_ENV, ready_write.as_raw_fd().to_string());
+        let handover = Handover::from_env().unwrap().unwrap();
+        let listeners: Vec<_> = handover
+            .listeners
+            .iter()
+            .map(|(name, listener)| (name.as_str(), listener.local_addr().unwrap()))
+            .collect();
+        assert_eq!(
+            listeners,
+            [
+                ("grpc", grpc.local_addr().unwrap()),
+                ("admin", admin.local_addr().unwrap())
+            ]
+        );
+
+        handover.ready().unwrap();
+        let mut buf = Vec::new();
+        File::from(ready_read).read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"1");
+
+        env::set_var(LISTEN_FDS_ENV, "nope:grpc");
+        let err = Handover::from_env().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        env::remove_var(LISTEN_FDS_ENV);
+        env::remove_var(READY_FD_ENV);
+    }
+}